@@ -0,0 +1,32 @@
+//!
+//! message.rs
+//! Fuzzes decoding of protocol messages: the JSON read back from logs by
+//! ProtocolMessage::from_string and the bincode sent over IPC channels.
+//! Decoding may fail but must not panic, and whatever decodes must survive
+//! a round trip unchanged.
+//!
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate bincode;
+extern crate serde_json;
+extern crate two_phase_commit;
+
+use two_phase_commit::message::{MessageType, ProtocolMessage};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(line) = std::str::from_utf8(data) {
+        let _ = MessageType::parse(line);
+        if let Ok(pm) = ProtocolMessage::from_string(line) {
+            let encoded = serde_json::to_string(&pm).unwrap();
+            let decoded = ProtocolMessage::from_string(&encoded).expect("re-encoded message does not decode");
+            assert_eq!(format!("{:?}", pm), format!("{:?}", decoded));
+        }
+    }
+
+    if let Ok(pm) = bincode::deserialize::<ProtocolMessage>(data) {
+        let encoded = bincode::serialize(&pm).unwrap();
+        let decoded: ProtocolMessage = bincode::deserialize(&encoded).expect("re-encoded message does not decode");
+        assert_eq!(format!("{:?}", pm), format!("{:?}", decoded));
+    }
+});
@@ -0,0 +1,35 @@
+//!
+//! oplog_segment.rs
+//! Fuzzes parsing of one OpLog segment in either of its formats (JSON lines
+//! or a binary log, selected by the leading bytes). Parsing may
+//! fail but must not panic, and cutting a segment short, as a crash or an
+//! interrupted copy does, must not turn a readable segment into an error.
+//!
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate two_phase_commit;
+
+use two_phase_commit::oplog;
+
+fuzz_target!(|data: &[u8]| {
+    let (records, _) = match oplog::parse_segment(data) {
+        Ok(parsed) => parsed,
+        Err(_) => return,
+    };
+    for rec in &records {
+        rec.hash();
+    }
+
+    // Any prefix of a segment that parses also parses, and starts with the
+    // same records. It can have one more: a torn JSON line cut right after
+    // its closing brace reads as a complete record.
+    let cut = data.len() / 2;
+    let prefix = match oplog::parse_segment(&data[..cut]) {
+        Ok((prefix, _)) => prefix,
+        Err(e) => panic!("segment truncated to {} bytes does not parse: {}", cut, e),
+    };
+    for (a, b) in prefix.iter().zip(records.iter()) {
+        assert_eq!(a.hash(), b.hash());
+    }
+});
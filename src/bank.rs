@@ -0,0 +1,229 @@
+//!
+//! bank.rs
+//! Bank-transfer workload. Accounts are sharded across participants by
+//! account number modulo the number of participants. Clients issue transfers
+//! between accounts on different participants; every participant holds the
+//! funds of a transfer when it votes to commit, applies its half on a global
+//! commit and releases the hold on a global abort. If 2PC is atomic, the sum
+//! of all balances never changes, which the checker verifies after the run:
+//! a participant that crashed before the decision arrived still holds its
+//! half of the transfer, which the checker applies if the coordinator
+//! committed, as the participant would on recovery.
+//!
+extern crate rand;
+extern crate serde;
+extern crate serde_json;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+
+use bank::rand::Rng;
+use message::Transfer;
+use tpcoptions::TPCOptions;
+
+///
+/// BankConfig
+/// Shape of the bank shared by clients and participants
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BankConfig {
+    pub num_accounts: u32,
+    pub initial_balance: i64,
+    pub num_participants: u32,
+}
+
+impl BankConfig {
+
+    ///
+    /// from_options(opts)
+    /// The bank configuration if the bank workload was selected
+    ///
+    pub fn from_options(opts: &TPCOptions) -> Option<BankConfig> {
+        if opts.workload != "bank" {
+            return None;
+        }
+        Some(BankConfig {
+            num_accounts: opts.accounts,
+            initial_balance: opts.initial_balance,
+            num_participants: opts.num_participants,
+        })
+    }
+
+    ///
+    /// owner(account)
+    /// Index of the participant that holds the account
+    ///
+    pub fn owner(&self, account: u32) -> u32 {
+        account % self.num_participants
+    }
+
+    ///
+    /// shard(participant)
+    /// Accounts held by the given participant
+    ///
+    pub fn shard(&self, participant: u32) -> Vec<u32> {
+        (participant..self.num_accounts).step_by(self.num_participants as usize).collect()
+    }
+
+    ///
+    /// random_transfer(rng)
+    /// A transfer of up to one initial balance between two accounts that live
+    /// on different participants (or just different accounts if there is
+    /// only one participant)
+    ///
+    pub fn random_transfer<R: Rng>(&self, rng: &mut R) -> Transfer {
+        let from = rng.gen_range(0, self.num_accounts);
+        let to = if self.num_participants > 1 {
+            let from_owner = self.owner(from);
+            let to_owner = (from_owner + rng.gen_range(1, self.num_participants)) % self.num_participants;
+            let shard = self.shard(to_owner);
+            shard[rng.gen_range(0, shard.len())]
+        } else {
+            (from + rng.gen_range(1, self.num_accounts)) % self.num_accounts
+        };
+        Transfer {
+            from,
+            to,
+            amount: rng.gen_range(1, self.initial_balance + 1),
+        }
+    }
+}
+
+///
+/// Bank
+/// One participant's shard of accounts and the holds of prepared transfers
+///
+#[derive(Debug)]
+pub struct Bank {
+    config: BankConfig,
+    index: u32,
+    balances: BTreeMap<u32, i64>,
+    pending: HashMap<String, Vec<(u32, i64)>>,   // txid -> (account, delta) to apply on commit
+}
+
+impl Bank {
+
+    ///
+    /// new(config, index)
+    /// The shard of participant `index`, every account at the initial balance
+    ///
+    pub fn new(config: BankConfig, index: u32) -> Bank {
+        Bank {
+            config,
+            index,
+            balances: config.shard(index).into_iter().map(|a| (a, config.initial_balance)).collect(),
+            pending: HashMap::new(),
+        }
+    }
+
+    ///
+    /// held(account)
+    /// Amount of an account promised to prepared but undecided transfers
+    ///
+    fn held(&self, account: u32) -> i64 {
+        self.pending.values()
+            .flat_map(|deltas| deltas.iter())
+            .filter(|(a, d)| *a == account && *d < 0)
+            .map(|(_, d)| -d)
+            .sum()
+    }
+
+    ///
+    /// prepare(txid, transfer)
+    /// Phase one: checks that this shard can do its half of the transfer and
+    /// holds the funds. Returns false (vote abort) on insufficient funds.
+    ///
+    pub fn prepare(&mut self, txid: &str, transfer: &Transfer) -> bool {
+        let mut deltas = Vec::new();
+        if self.config.owner(transfer.from) == self.index {
+            let available = self.balances[&transfer.from] - self.held(transfer.from);
+            if available < transfer.amount {
+                return false;
+            }
+            deltas.push((transfer.from, -transfer.amount));
+        }
+        if self.config.owner(transfer.to) == self.index {
+            deltas.push((transfer.to, transfer.amount));
+        }
+        self.pending.insert(txid.to_string(), deltas);
+        true
+    }
+
+    ///
+    /// commit(txid)
+    /// Phase two, global commit: applies this shard's half of the transfer
+    ///
+    pub fn commit(&mut self, txid: &str) {
+        if let Some(deltas) = self.pending.remove(txid) {
+            for (account, delta) in deltas {
+                *self.balances.get_mut(&account).unwrap() += delta;
+            }
+        }
+    }
+
+    ///
+    /// abort(txid)
+    /// Phase two, global abort: releases the hold of the transfer
+    ///
+    pub fn abort(&mut self, txid: &str) {
+        self.pending.remove(txid);
+    }
+
+    ///
+    /// snapshot()
+    /// Initial and current balances of this shard, for the checker
+    ///
+    pub fn snapshot(&self) -> BankSnapshot {
+        BankSnapshot {
+            initial_total: self.config.initial_balance * self.balances.len() as i64,
+            balances: self.balances.clone(),
+            in_doubt: self.pending.len() as u64,
+            pending: self.pending.iter().map(|(txid, deltas)| (txid.clone(), deltas.clone())).collect(),
+        }
+    }
+}
+
+///
+/// BankSnapshot
+/// Balances one participant ended the run with
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct BankSnapshot {
+    pub initial_total: i64,
+    pub balances: BTreeMap<u32, i64>,
+    pub in_doubt: u64,      // prepared transfers that never got a decision
+    #[serde(default)]
+    pub pending: BTreeMap<String, Vec<(u32, i64)>>,   // Their (account, delta) by txid
+}
+
+impl BankSnapshot {
+
+    ///
+    /// write_json(path)
+    /// Saves the snapshot as pretty-printed JSON
+    ///
+    pub fn write_json(&self, path: &str) -> io::Result<()> {
+        let f = File::create(path)?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+
+    ///
+    /// from_file(path)
+    /// Reads a snapshot saved with write_json
+    ///
+    pub fn from_file(path: &str) -> io::Result<BankSnapshot> {
+        let f = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(f))?)
+    }
+}
+
+///
+/// balances_path(log_path, id)
+/// Location of the final balances of participant `id` in `log_path`
+///
+pub fn balances_path(log_path: &str, id: &str) -> String {
+    format!("{}//{}.balances.json", log_path, id)
+}
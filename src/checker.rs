@@ -11,57 +11,40 @@ extern crate stderrlog;
 extern crate clap;
 extern crate ctrlc;
 
-use std::collections::HashMap;
+use std::collections::HashSet;
 
-use message;
+use bank;
+use bank::BankSnapshot;
 use message::MessageType;
-use message::ProtocolMessage;
+use oplog;
 use oplog::OpLog;
+use tpcoptions::TPCOptions;
 
 ///
 /// check_participant()
 ///
-/// Given a participant name and HashMaps that represents the log files for the
-/// participant and coordinator (already filtered for commit records), check
-/// that the committed and aborted transactions are agreed upon by the two.
+/// Given a participant name and the logs of the participant and coordinator,
+/// check that the committed and aborted transactions are agreed upon by the
+/// two.
 ///
 /// <params>
 ///     participant: name of participant (label)
-///     ncommit: number of committed transactions from coordinator
-///     nabort: number of aborted transactions from coordinator
-///     ccommitted: map of committed transactions from coordinator
-///     plog: map of participant operations
+///     coord_log: log of the coordinator
+///     participant_log: log of the participant
 ///
 fn check_participant(
-    participant: &String,
-    num_commit: usize,
-    num_abort: usize,
-    coord_committed: &HashMap<u32, ProtocolMessage>,
-    participant_log: &HashMap<u32, ProtocolMessage>
+    participant: &str,
+    coord_log: &OpLog,
+    participant_log: &OpLog
     ) -> bool {
 
     let mut result = true;
 
-    // Filter the participant log for Global Commits, Local Commits, and Aborted
-    let participant_commit_map: HashMap<u32, message::ProtocolMessage> =
-        participant_log.iter()
-        .filter(|e| (*e.1).mtype == MessageType::CoordinatorCommit)
-        .map(|(k,v)| (k.clone(), v.clone()))
-        .collect();
-    let participant_local_commit_map: HashMap<u32, message::ProtocolMessage> =
-        participant_log.iter()
-        .filter(|e| (*e.1).mtype == MessageType::ParticipantVoteCommit)
-        .map(|(k,v)| (k.clone(), v.clone()))
-        .collect();
-    let participant_abort_map: HashMap<u32, message::ProtocolMessage> =
-        participant_log.iter()
-        .filter(|e| (*e.1).mtype == MessageType::CoordinatorAbort)
-        .map(|(k,v)| (k.clone(), v.clone()))
-        .collect();
-
-    let num_participant_commit = participant_commit_map.len();
-    let num_participant_local_commit = participant_local_commit_map.len();
-    let num_participant_abort = participant_abort_map.len();
+    let num_commit = coord_log.count(MessageType::CoordinatorCommit);
+    let num_abort = coord_log.count(MessageType::CoordinatorAbort);
+    let num_participant_commit = participant_log.count(MessageType::CoordinatorCommit);
+    let num_participant_local_commit = participant_log.count(MessageType::ParticipantVoteCommit);
+    let num_participant_abort = participant_log.count(MessageType::CoordinatorAbort);
 
     result &= num_participant_commit <= num_commit;
     result &= num_participant_local_commit >= num_commit;
@@ -71,32 +54,21 @@ fn check_participant(
     assert!(num_commit <= num_participant_local_commit);
     assert!(num_abort >= num_participant_abort);
 
-    for (_, coord_msg) in coord_committed.iter() {
-        let txid = coord_msg.txid.clone();
-        let mut _found_txid = 0;
-        let mut found_local_txid = 0;
-        for (_, participant_msg) in participant_commit_map.iter() {
-            if participant_msg.txid == txid {
-                _found_txid += 1;
-            }
-        }
-
-        for (_, participant_msg) in participant_local_commit_map.iter() {
-            // Handle the case where the participant simply doesn't get the
-            // global commit message from the coordinator. If the coordinator
-            // committed the transaction, the participant has to have voted in
-            // favor. Namely, when _found_txid != found_local_txid.
-            if participant_msg.txid == txid {
-                found_local_txid += 1;
-            }
-        }
+    for coord_rec in coord_log.by_type(MessageType::CoordinatorCommit) {
+        // If the coordinator committed the transaction, the participant has
+        // to have voted in favor, whether or not it got the global commit.
+        let found_local_txid = participant_log.by_txid(&coord_rec.message.txid)
+            .filter(|rec| rec.message.mtype == MessageType::ParticipantVoteCommit)
+            .count();
 
         // Exactly one commit of txid per participant
         result &= found_local_txid == 1;
         assert!(found_local_txid == 1);
     }
+
+    result &= check_causality(participant, coord_log, participant_log);
     println!("{} OK: Committed: {} == {} (Committed-global), Aborted: {} <= {} (Aborted-global)",
-             participant.clone(),
+             participant,
              num_participant_commit,
              num_commit,
              num_participant_abort,
@@ -104,6 +76,42 @@ fn check_participant(
     result
 }
 
+///
+/// check_causality()
+///
+/// Uses the Lamport times in the logs to check that no participant learned
+/// of a decision before the coordinator made it, and that the decision a
+/// participant received is the one the coordinator logged. Records without a
+/// Lamport time (version 1 records) are skipped.
+///
+fn check_causality(participant: &str, coord_log: &OpLog, participant_log: &OpLog) -> bool {
+    let mut violations = Vec::new();
+    let decisions = participant_log.by_type(MessageType::CoordinatorCommit)
+        .chain(participant_log.by_type(MessageType::CoordinatorAbort))
+        .filter(|rec| rec.message.lamport > 0);
+    for received in decisions {
+        let decided = coord_log.by_txid(&received.message.txid)
+            .find(|rec| rec.message.mtype == MessageType::CoordinatorCommit || rec.message.mtype == MessageType::CoordinatorAbort);
+        match decided {
+            None => violations.push(format!("{}: {:?} (LSN {}) was never decided by the coordinator",
+                                            received.message.txid, received.message.mtype, received.lsn)),
+            Some(ref d) if d.message.mtype != received.message.mtype =>
+                violations.push(format!("{}: received {:?} but the coordinator decided {:?}",
+                                        received.message.txid, received.message.mtype, d.message.mtype)),
+            Some(ref d) if d.message.lamport > 0 && received.message.lamport <= d.message.lamport =>
+                violations.push(format!("{}: {:?} at Lamport time {} is not after the decision at {}",
+                                        received.message.txid, received.message.mtype, received.message.lamport, d.message.lamport)),
+            _ => {},
+        }
+    }
+
+    for v in &violations {
+        println!("{} CAUSALITY VIOLATION: {}", participant, v);
+    }
+    assert!(violations.is_empty(), "{} causality violations at {}", violations.len(), participant);
+    violations.is_empty()
+}
+
 ///
 /// check_last_run()
 ///
@@ -117,11 +125,13 @@ fn check_participant(
 ///     num_participants: Number of participants
 ///     log_path: Directory for client, participant, and coordinator logs
 ///
+/// Returns false if a log could not be loaded.
+///
 pub fn check_last_run(
     num_clients: u32,
     num_requests: u32,
     num_participants: u32,
-    log_path: &String) {
+    log_path: &String) -> bool {
 
         info!("Checking 2PC run:  {} requests * {} clients, {} participants",
               num_requests,
@@ -129,35 +139,159 @@ pub fn check_last_run(
               num_participants);
 
         let coord_log_path = format!("{}//{}", log_path, "coordinator.log");
-        let coord_log = OpLog::from_file(coord_log_path);
-
-        let lock = coord_log.arc();
-        let coord_map = lock.lock().unwrap();
-
-        // Filter coordinator logs for Commit and Abort
-        let committed: HashMap<u32, message::ProtocolMessage> =
-            coord_map.iter()
-            .filter(|e| (*e.1).mtype == MessageType::CoordinatorCommit)
-            .map(|(k,v)| (k.clone(), v.clone()))
-            .collect();
-        let aborted: HashMap<u32, message::ProtocolMessage> =
-            coord_map.iter()
-            .filter(|e| (*e.1).mtype == MessageType::CoordinatorAbort)
-            .map(|(k,v)| (k.clone(), v.clone()))
-            .collect();
-
-        let num_commit = committed.len();
-        let num_abort = aborted.len();
+        let coord_log = match load_log(&coord_log_path) {
+            Some(l) => l,
+            None => return false,
+        };
 
         // Iterate and check each participant
+        let mut result = true;
         for pid in 0..num_participants {
             let participant_id_str = format!("participant_{}", pid);
             let participant_log_path = format!("{}//{}.log", log_path, participant_id_str);
-            let participant_oplog = OpLog::from_file(participant_log_path);
-            let participant_lock = participant_oplog.arc();
-            let participant_log = participant_lock.lock().unwrap();
-            check_participant(&participant_id_str, num_commit, num_abort, &committed, &participant_log);
+            let participant_log = match load_log(&participant_log_path) {
+                Some(l) => l,
+                None => {
+                    result = false;
+                    continue;
+                }
+            };
+            result &= check_participant(&participant_id_str, &coord_log, &participant_log);
         }
+        result
     }
 
+///
+/// load_log()
+///
+/// Loads an OpLog for checking, reporting a torn tail that had to be
+/// discarded or why the log could not be loaded at all. Checkpointed logs
+/// are refused: they lack the decisions and votes of every transaction
+/// decided before the checkpoint.
+///
+fn load_log(path: &str) -> Option<OpLog> {
+    match OpLog::from_file(path.to_string()) {
+        Ok(ref log) if log.checkpointed() => {
+            error!("Cannot check log \"{}\": it was checkpointed, dropping the records of decided transactions; rerun with --checkpoint_interval=0", path);
+            None
+        },
+        Ok(log) => {
+            if let Some(tail) = log.torn_tail() {
+                println!("{}: discarded {} bytes of torn tail at offset {} ({}), saved to {}",
+                         path, tail.discarded_bytes, tail.offset, tail.reason, tail.saved_to.as_ref().map_or("-", |s| s.as_str()));
+            }
+            Some(log)
+        },
+        Err(e) => {
+            error!("Failed to load log \"{}\": {}", path, e);
+            None
+        }
+    }
+}
 
+
+
+///
+/// check_bank()
+///
+/// For runs of the bank workload: loads the final balances every participant
+/// left in log_path and checks the global invariant that money was neither
+/// created nor destroyed (the sum of all balances equals the initial total)
+/// and that no account was overdrawn. Transfers a participant still held in
+/// doubt are applied if the coordinator logged a commit for them, as the
+/// participant would on recovery, and otherwise left out.
+///
+/// <params>
+///     num_participants: Number of participants
+///     log_path: Directory for client, participant, and coordinator logs
+///
+pub fn check_bank(num_participants: u32, log_path: &str) -> bool {
+    let mut initial_total = 0;
+    let mut final_total = 0;
+    let mut in_doubt = 0;
+    let mut recovered = 0;
+    let mut overdrawn = Vec::new();
+    let coord_log_path = format!("{}//{}", log_path, "coordinator.log");
+    let committed: HashSet<String> = match OpLog::read_only(coord_log_path.clone()) {
+        Ok(log) => log.by_type(MessageType::CoordinatorCommit).map(|rec| rec.message.txid).collect(),
+        Err(e) => {
+            error!("Failed to load log \"{}\": {}", coord_log_path, e);
+            return false;
+        }
+    };
+    for pid in 0..num_participants {
+        let participant_id_str = format!("participant_{}", pid);
+        let path = bank::balances_path(log_path, &participant_id_str);
+        let snapshot = match BankSnapshot::from_file(&path) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("Failed to load balances \"{}\": {}", path, e);
+                return false;
+            }
+        };
+        let mut balances = snapshot.balances;
+        for (txid, deltas) in snapshot.pending.iter().filter(|(txid, _)| committed.contains(*txid)) {
+            recovered += 1;
+            for (account, delta) in deltas {
+                *balances.entry(*account).or_insert(0) += delta;
+            }
+            debug!("{}: applied in-doubt transfer {} committed by the coordinator", participant_id_str, txid);
+        }
+        initial_total += snapshot.initial_total;
+        final_total += balances.values().sum::<i64>();
+        in_doubt += snapshot.in_doubt;
+        overdrawn.extend(balances.iter().filter(|(_, b)| **b < 0).map(|(a, b)| (*a, *b)));
+    }
+
+    let result = initial_total == final_total && overdrawn.is_empty();
+    println!("bank {}: Balance total: {} == {} (initial), Overdrawn accounts: {:?}, In doubt: {} ({} committed, applied)",
+             if result { "OK" } else { "FAILED" },
+             final_total,
+             initial_total,
+             overdrawn,
+             in_doubt,
+             recovered);
+    result
+}
+
+///
+/// verify_logs()
+///
+/// Verifies the hash chain of opts.log_file, or of the coordinator and every
+/// participant log in opts.log_path, and reports the first broken link of
+/// each. Returns false if any chain is broken or a log could not be read.
+///
+pub fn verify_logs(opts: &TPCOptions) -> bool {
+    let paths = if !opts.log_file.is_empty() {
+        vec![opts.log_file.clone()]
+    } else {
+        let mut paths = vec![format!("{}//{}", opts.log_path, "coordinator.log")];
+        paths.extend((0..opts.num_participants).map(|pid| format!("{}//participant_{}.log", opts.log_path, pid)));
+        paths
+    };
+
+    let mut result = true;
+    for path in paths {
+        match oplog::verify_chain(&path) {
+            Ok(report) => {
+                if let Some(ref tail) = report.torn_tail {
+                    println!("{}: torn tail after the last record ({})", path, tail);
+                }
+                match report.broken {
+                    Some(b) => {
+                        result = false;
+                        println!("{} BROKEN: record LSN {} in segment {} has prev_hash {}, expected {} (it or the record before it was altered)",
+                                 path, b.lsn, b.segment, b.found, b.expected);
+                    },
+                    None => println!("{} OK: {} records, {} without hash (version 1)",
+                                     path, report.records, report.unchained),
+                }
+            },
+            Err(e) => {
+                result = false;
+                error!("Failed to read log \"{}\": {}", path, e);
+            }
+        }
+    }
+    result
+}
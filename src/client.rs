@@ -6,20 +6,32 @@ extern crate ipc_channel;
 extern crate log;
 extern crate stderrlog;
 
+extern crate rand;
+
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
 
+use client::rand::SeedableRng;
+use client::rand::rngs::StdRng;
+
 use client::ipc_channel::ipc::IpcReceiver as Receiver;
-use client::ipc_channel::ipc::TryRecvError;
 use client::ipc_channel::ipc::IpcSender as Sender;
 
+use bank::BankConfig;
+use clock::LamportClock;
 use message;
 use message::MessageType;
-use message::RequestStatus;
 use message::ProtocolMessage;
+use stats::{LatencyStats, StatsReport};
+use trace::Tracer;
+use tracing::{event, span, Level};
+use workload::Arrival;
+
+/// Upper bound on how long an open-loop client sleeps between polls for results
+const POLL_INTERVAL_MS: u64 = 1;
 
 // Client state and primitives for communicating with the coordinator
 #[derive(Debug)]
@@ -31,7 +43,14 @@ pub struct Client {
     pub num_requests: u32,
     pub successful_ops: u32,  // Add this line
     pub failed_ops: u32,      // Add this line
-    pub unknown_ops: u32, 
+    pub unknown_ops: u32,
+    arrival: Arrival,
+    bank: Option<BankConfig>,
+    sent_at: HashMap<String, Instant>,  // txid -> time the request was issued
+    stats: LatencyStats,
+    tracer: Tracer,
+    clock: LamportClock,
+    rng: StdRng,
     op: u32
 }
 
@@ -60,20 +79,38 @@ impl Client {
                running: Arc<AtomicBool>,
                tx: Sender<ProtocolMessage>,
                rx: Receiver<ProtocolMessage>,
-               n_requests: u32) -> Client {
+               n_requests: u32,
+               arrival: Arrival,
+               bank: Option<BankConfig>) -> Client {
+        let tracer = Tracer::new(&id_str);
         Client {
-            id_str: id_str,
-            running: running,
-            tx: tx,
-            rx: Mutex::new(rx), 
-            num_requests: n_requests, 
+            id_str,
+            running,
+            tx,
+            rx: Mutex::new(rx),
+            num_requests: n_requests,
             successful_ops: 0,
             failed_ops: 0,
             unknown_ops: 0,
+            arrival,
+            bank,
+            sent_at: HashMap::new(),
+            stats: LatencyStats::new(),
+            tracer,
+            clock: LamportClock::new(),
+            rng: StdRng::from_entropy(),
             op: 0
         }
     }
 
+    ///
+    /// seed()
+    /// Make the client's transfers and arrival gaps reproducible
+    ///
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
     ///
     /// wait_for_exit_signal(&mut self)
     /// Wait until the running flag is set by the CTRL-C handler
@@ -95,16 +132,23 @@ impl Client {
     pub fn send_next_operation(&mut self) {
 
         // Create a new request with a unique TXID.
-        self.op = self.op + 1;
+        self.op += 1;
         let txid = format!("{}_op_{}", self.id_str.clone(), self.op);
 
-        let pm = message::ProtocolMessage::generate(message::MessageType::ClientRequest,
-                                                    txid.clone(),
-                                                    self.id_str.clone(),
-                                                    self.op);
-        info!("{}::Sending operation #{}", self.id_str.clone(), self.op);
+        let mut pm = message::ProtocolMessage::generate(message::MessageType::ClientRequest,
+                                                        txid.clone(),
+                                                        self.id_str.clone(),
+                                                        self.op);
+        if let Some(bank) = self.bank {
+            pm.transfer = Some(bank.random_transfer(&mut self.rng));
+        }
+        pm.lamport = self.clock.tick();
+        let span = span!(Level::INFO, "transaction", role = "client", txid = %txid, senderid = %self.id_str);
+        let _entered = span.enter();
+        event!(Level::INFO, phase = "request", opid = self.op, lamport = pm.lamport, "sending request");
 
         // TODO
+        self.sent_at.insert(txid, Instant::now());
         self.tx.send(pm).expect("Failed to send operation");
         trace!("{}::Sent operation #{}", self.id_str.clone(), self.op);
     }
@@ -120,32 +164,74 @@ impl Client {
         info!("{}::Receiving Coordinator Result", self.id_str.clone());
 
         // TODO
-        if let Ok(rx) = self.rx.lock() {
-
-            loop {
-                match rx.try_recv() {
-                    Ok(message) => {
-                        match message.mtype {
-                            MessageType::ClientResultCommit => self.successful_ops += 1,
-                            MessageType::ClientResultAbort => self.failed_ops += 1,
-                            MessageType::CoordinatorExit => self.running.store(false, Ordering::SeqCst),
-                            _ => {
-                                // Handle all other message types
-                            }
+        let message = match self.rx.lock() {
+            Ok(rx) => {
+                loop {
+                    match rx.try_recv() {
+                        Ok(message) => break Some(message),
+                        Err(_e) => {
+                            trace!("Client receive error.");
                         }
-                        // let mess = format!("Received result {:?}", message);
-                        // println!("{}", mess);
-                        break;
-                    },
-                    Err(_e) => {
-                        trace!("Client receive error.");
                     }
-                } 
-               
+                }
+            },
+            Err(_) => None,
+        };
+        if let Some(message) = message {
+            self.handle_result(message);
+        }
+    }
+
+    ///
+    /// poll_results()
+    /// Handle every result that has already arrived without blocking.
+    /// Returns the number of messages handled.
+    ///
+    pub fn poll_results(&mut self) -> u32 {
+        let mut received = Vec::new();
+        if let Ok(rx) = self.rx.lock() {
+            while let Ok(message) = rx.try_recv() {
+                received.push(message);
+            }
+        }
+        let n = received.len() as u32;
+        for message in received {
+            self.handle_result(message);
+        }
+        n
+    }
+
+    ///
+    /// handle_result()
+    /// Account for a single message received from the coordinator
+    ///
+    fn handle_result(&mut self, message: ProtocolMessage) {
+        let received = self.clock.observe(message.lamport);
+        let span = span!(Level::INFO, "transaction", role = "client", txid = %message.txid, senderid = %message.senderid);
+        let _entered = span.enter();
+        event!(Level::INFO, phase = "result", result = ?message.mtype, lamport = received, "received result");
+        if let Some(sent) = self.sent_at.remove(&message.txid) {
+            self.stats.record("client_e2e", &message.txid, sent.elapsed());
+            self.tracer.async_span("client_request", &message.txid, sent, Instant::now());
+        }
+        match message.mtype {
+            MessageType::ClientResultCommit => self.successful_ops += 1,
+            MessageType::ClientResultAbort => self.failed_ops += 1,
+            MessageType::CoordinatorExit => self.running.store(false, Ordering::SeqCst),
+            _ => {
+                // Handle all other message types
             }
         }
     }
 
+    ///
+    /// outstanding()
+    /// Number of issued requests whose result has not been received yet
+    ///
+    fn outstanding(&self) -> u32 {
+        self.op - self.successful_ops - self.failed_ops - self.unknown_ops
+    }
+
     ///
     /// report_status()
     /// Report the abort/commit/unknown status (aggregate) of all transaction
@@ -153,8 +239,32 @@ impl Client {
     ///
     pub fn report_status(&mut self) {
         // TODO: Collect actual stats
-        println!("{:16}:\tCommitted: {:6}\tAborted: {:6}", 
-                 self.id_str, self.successful_ops, self.failed_ops);
+        println!("{:16}:\tCommitted: {:6}\tAborted: {:6}\tUnknown: {:6}",
+                 self.id_str, self.successful_ops, self.failed_ops, self.unknown_ops);
+        self.stats_report().print();
+    }
+
+    ///
+    /// stats_report()
+    /// Counters, client-observed latency histogram and the rate at which
+    /// this client received results
+    ///
+    pub fn stats_report(&self) -> StatsReport {
+        let counters = [
+            ("committed", self.successful_ops),
+            ("aborted", self.failed_ops),
+            ("unknown", self.unknown_ops),
+        ];
+        let completed = (self.successful_ops + self.failed_ops) as u64;
+        self.stats.report(&self.id_str, &counters, completed)
+    }
+
+    ///
+    /// tracer()
+    /// Trace events recorded so far
+    ///
+    pub fn tracer(&self) -> &Tracer {
+        &self.tracer
     }
 
     ///
@@ -165,16 +275,71 @@ impl Client {
     ///       exit signal before returning from the protocol method!
     ///
     pub fn protocol(&mut self, n_requests: u32) {
-        // TODO
+        self.stats.restart_clock();
+        if self.arrival.is_open_loop() {
+            self.protocol_open_loop(n_requests);
+        } else {
+            self.protocol_closed_loop(n_requests);
+        }
+        self.report_status();
+    }
+
+    ///
+    /// protocol_closed_loop()
+    /// Issue one request at a time and wait for its result before thinking
+    /// and issuing the next one
+    ///
+    fn protocol_closed_loop(&mut self, n_requests: u32) {
         for _ in 0..n_requests {
             if !self.running.load(Ordering::SeqCst) {
                 break;
             }
             self.send_next_operation();
             self.recv_result();
-            // This sleep is to prevent bombarding the coordinator too quickly.
-            thread::sleep(Duration::from_millis(100));
+            // Think time keeps the client from bombarding the coordinator.
+            thread::sleep(self.arrival.next_delay(&mut self.rng));
+        }
+    }
+
+    ///
+    /// protocol_open_loop()
+    /// Issue requests at the times given by the arrival process, regardless
+    /// of how many results are outstanding, then wait for the stragglers.
+    /// Results that never arrive are counted as unknown.
+    ///
+    fn protocol_open_loop(&mut self, n_requests: u32) {
+        let mut next_arrival = Instant::now();
+        let mut issued = 0;
+        while issued < n_requests && self.running.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= next_arrival {
+                self.send_next_operation();
+                issued += 1;
+                // Schedule from the previous arrival rather than from now so
+                // that sending delays do not lower the offered rate.
+                next_arrival += self.arrival.next_delay(&mut self.rng);
+            } else {
+                self.poll_results();
+                let poll = Duration::from_millis(POLL_INTERVAL_MS);
+                thread::sleep(std::cmp::min(next_arrival - now, poll));
+            }
+        }
+
+        let patience = self.arrival.idle_timeout() * 2;
+        let mut last_result = Instant::now();
+        while self.outstanding() > 0 && self.running.load(Ordering::SeqCst) {
+            if self.poll_results() > 0 {
+                last_result = Instant::now();
+            } else if last_result.elapsed() >= patience {
+                break;
+            } else {
+                thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            }
+        }
+        let outstanding = self.outstanding();
+        if outstanding > 0 {
+            warn!("{}::{} results never arrived", self.id_str.clone(), outstanding);
+            self.unknown_ops += outstanding;
         }
-        self.report_status();
     }
 }
@@ -0,0 +1,54 @@
+//!
+//! clock.rs
+//! Lamport clocks for ordering protocol events across processes. Every role
+//! keeps one clock: it ticks before a message is sent and the message carries
+//! the new time, and on receipt it jumps past the time carried by the
+//! message. If event a causally precedes event b, a's time is lower than b's.
+//!
+
+///
+/// LamportClock
+/// Logical clock of one process; time 0 means "no event yet"
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LamportClock {
+    time: u64,
+}
+
+impl LamportClock {
+
+    ///
+    /// new()
+    /// A clock at time 0
+    ///
+    pub fn new() -> LamportClock {
+        LamportClock { time: 0 }
+    }
+
+    ///
+    /// time()
+    /// Time of the latest event
+    ///
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    ///
+    /// tick()
+    /// Advances the clock for a local or send event and returns its time
+    ///
+    pub fn tick(&mut self) -> u64 {
+        self.time += 1;
+        self.time
+    }
+
+    ///
+    /// observe(stamp)
+    /// Advances the clock past the time carried by a received message and
+    /// returns the time of the receive event
+    ///
+    pub fn observe(&mut self, stamp: u64) -> u64 {
+        self.time = self.time.max(stamp) + 1;
+        self.time
+    }
+}
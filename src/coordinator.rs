@@ -7,7 +7,9 @@ extern crate stderrlog;
 extern crate rand;
 extern crate ipc_channel;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -19,15 +21,22 @@ use coordinator::ipc_channel::ipc::IpcReceiver as Receiver;
 use coordinator::ipc_channel::ipc::TryRecvError;
 use coordinator::ipc_channel::ipc::channel;
 use ipc_channel::ipc::IpcOneShotServer;
+use clock::LamportClock;
 use message;
 use message::MessageType;
 use message::ProtocolMessage;
 use message::RequestStatus;
+use metrics::MetricsPublisher;
+use monitor::{OnViolation, SafetyMonitor, Violation};
 use oplog;
+use protocol::{transition_key, CoordinatorAction, CoordinatorInput, CoordinatorTxn, REJECTED};
+use stats::{LatencyStats, StatsReport};
+use trace::Tracer;
+use tracing::{event, span, Level};
 
 /// CoordinatorState
 /// States for 2PC state machine
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum CoordinatorState {
     Quiescent,
     ReceivedRequest,
@@ -37,11 +46,49 @@ pub enum CoordinatorState {
     SentGlobalDecision
 }
 
+impl CoordinatorState {
+    /// Every state, in protocol order
+    pub const ALL: [CoordinatorState; 6] = [
+        CoordinatorState::Quiescent,
+        CoordinatorState::ReceivedRequest,
+        CoordinatorState::ProposalSent,
+        CoordinatorState::ReceivedVotesAbort,
+        CoordinatorState::ReceivedVotesCommit,
+        CoordinatorState::SentGlobalDecision,
+    ];
+}
+
+/// VoteMix
+/// Number of commit and abort votes received from one participant
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VoteMix {
+    pub commit: u32,
+    pub abort: u32,
+}
+
+/// CoordinatorProgress
+/// Snapshot of the coordinator's counters and state, republished after every
+/// step of the protocol so that other threads (the dashboard) can follow a
+/// run while it is going
+#[derive(Clone, Debug)]
+pub struct CoordinatorProgress {
+    pub state: CoordinatorState,
+    pub current: String,                    // Transaction being handled, empty when idle
+    pub global_commit: u32,
+    pub global_abort: u32,
+    pub unknown: u32,                       // Votes that timed out
+    pub in_flight: u32,                     // Requests received but not decided yet
+    pub votes: BTreeMap<String, VoteMix>,   // Votes received per participant
+}
+
 /// Coordinator
 /// Struct maintaining state for coordinator
 #[derive(Debug)]
 pub struct Coordinator {
     state: CoordinatorState,
+    current: String,
+    txns: HashMap<String, CoordinatorTxn>,
+    voters: HashMap<String, usize>,
     running: Arc<AtomicBool>,
     log: oplog::OpLog,
     num_request: u32,
@@ -49,6 +96,18 @@ pub struct Coordinator {
     clients:HashMap<String, Sender<ProtocolMessage>>,
     client_rx: Receiver<ProtocolMessage>,
     participant_rx: Receiver<ProtocolMessage>,
+    idle_timeout: Duration,
+    stats: LatencyStats,
+    tracer: Tracer,
+    clock: LamportClock,
+    votes: BTreeMap<String, VoteMix>,
+    progress: Arc<Mutex<CoordinatorProgress>>,
+    metrics: Option<MetricsPublisher>,
+    monitor: SafetyMonitor,
+    on_violation: OnViolation,
+    send_failures: u32,
+    illegal_transitions: u32,
+    transitions: BTreeMap<String, u64>,
     global_commit: u32,
     commit: u32,
     global_abort: u32,
@@ -75,17 +134,29 @@ impl Coordinator {
     /// <params>
     ///     log_path: directory for log files --> create a new log there.
     ///     r: atomic bool --> still running?
+    ///     log_config: format (and other settings) of the new log
+    ///     idle_timeout: how long to wait for a new client request before
+    ///         assuming the clients are done
+    ///     on_violation: what to do when the safety monitor catches a
+    ///         broken invariant
     ///
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         log_path: String,
+        log_config: oplog::LogConfig,
         r: &Arc<AtomicBool>,
         num_request: u32,
         client_rx: Receiver<ProtocolMessage>,
-        participant_rx: Receiver<ProtocolMessage>) -> Coordinator {
+        participant_rx: Receiver<ProtocolMessage>,
+        idle_timeout: Duration,
+        on_violation: OnViolation) -> Coordinator {
 
         Coordinator {
             state: CoordinatorState::Quiescent,
-            log: oplog::OpLog::new(log_path),
+            current: String::new(),
+            txns: HashMap::new(),
+            voters: HashMap::new(),
+            log: oplog::OpLog::with_config(log_path, log_config),
             running: r.clone(),
             // TODO
             participants: HashMap::new(),
@@ -93,6 +164,26 @@ impl Coordinator {
             num_request,
             client_rx,
             participant_rx,
+            idle_timeout,
+            stats: LatencyStats::new(),
+            tracer: Tracer::new("coordinator"),
+            clock: LamportClock::new(),
+            votes: BTreeMap::new(),
+            progress: Arc::new(Mutex::new(CoordinatorProgress {
+                state: CoordinatorState::Quiescent,
+                current: String::new(),
+                global_commit: 0,
+                global_abort: 0,
+                unknown: 0,
+                in_flight: 0,
+                votes: BTreeMap::new(),
+            })),
+            metrics: None,
+            monitor: SafetyMonitor::new(),
+            on_violation,
+            send_failures: 0,
+            illegal_transitions: 0,
+            transitions: BTreeMap::new(),
             global_commit: 0,
             global_abort: 0,
             commit: 0,
@@ -115,8 +206,12 @@ impl Coordinator {
         // }
         // TODO
         // Store the sender and receiver in the participants HashMap.
+        let index = self.voters.len();
+        self.voters.entry(name.to_string()).or_insert(index);
         self.participants.insert(name.to_string(), tx);
-        
+        self.votes.insert(name.to_string(), VoteMix::default());
+        self.monitor.participant_joined(name);
+        self.publish();
     }
 
     ///
@@ -144,12 +239,177 @@ impl Coordinator {
         // TODO: Collect actual stats
         println!("Global\nCommitted: {:6}\tAborted: {:6}\n=======\nCommitted: {:6}\tAborted: {:6}\tUnknown: {:6}", 
         self.global_commit,self.global_abort, self.commit, self.abort, self.unknown);
+        self.stats_report().print();
+    }
+
+    ///
+    /// stats_report()
+    /// Counters, latency histograms of the prepare round trip, the decision
+    /// fan-out and the whole transaction as seen by the coordinator, plus
+    /// throughput
+    ///
+    pub fn stats_report(&self) -> StatsReport {
+        let counters = [
+            ("global_commit", self.global_commit),
+            ("global_abort", self.global_abort),
+            ("commit", self.commit),
+            ("abort", self.abort),
+            ("unknown", self.unknown),
+            ("send_failures", self.send_failures),
+            ("invariant_violations", self.monitor.violations()),
+            ("illegal_transitions", self.illegal_transitions),
+            ("log_appends", self.log.appends() as u32),
+            ("log_syncs", self.log.syncs() as u32),
+            ("log_checkpoints", self.log.checkpoints() as u32),
+        ];
+        let decided = (self.global_commit + self.global_abort) as u64;
+        let mut report = self.stats.report("coordinator", &counters, decided);
+        report.state = format!("{:?}", self.state);
+        report.states = CoordinatorState::ALL.iter()
+            .map(|s| (format!("{:?}", s), self.txns.values().filter(|t| t.state == *s).count() as u64))
+            .collect();
+        report.transitions = self.transitions.clone();
+        report
+    }
+
+    ///
+    /// state()
+    /// State of the transaction being handled, Quiescent when idle
+    ///
+    pub fn state(&self) -> CoordinatorState {
+        self.state
+    }
+
+    ///
+    /// txn_state(txid)
+    /// Where txid is in the state machine, if the coordinator has seen it
+    ///
+    pub fn txn_state(&self, txid: &str) -> Option<CoordinatorState> {
+        self.txns.get(txid).map(|t| t.state)
+    }
+
+    ///
+    /// tracer()
+    /// Trace events recorded so far
+    ///
+    pub fn tracer(&self) -> &Tracer {
+        &self.tracer
+    }
+
+    ///
+    /// progress()
+    /// Handle on the progress snapshot, updated as the protocol runs
+    ///
+    pub fn progress(&self) -> Arc<Mutex<CoordinatorProgress>> {
+        Arc::clone(&self.progress)
+    }
+
+    ///
+    /// serve_metrics()
+    /// Expose this coordinator's counters and latency histograms on addr
+    ///
+    pub fn serve_metrics(&mut self, addr: SocketAddr) -> io::Result<()> {
+        self.metrics = Some(MetricsPublisher::start(addr, "coordinator")?);
+        self.publish_metrics(true);
+        Ok(())
+    }
+
+    ///
+    /// publish_metrics()
+    /// Refresh the metrics endpoint, at most every few hundred ms unless forced
+    ///
+    fn publish_metrics(&mut self, force: bool) {
+        if self.metrics.as_ref().is_some_and(|m| force || m.due()) {
+            let report = self.stats_report().without_samples();
+            if let Some(metrics) = self.metrics.as_mut() {
+                metrics.publish(report);
+            }
+        }
+    }
+
+    ///
+    /// monitor_result()
+    /// Log a violation caught by the safety monitor with its full context,
+    /// and stop the run if asked to. Returns whether the protocol may go on
+    /// with the step that was checked.
+    ///
+    fn monitor_result(&mut self, result: Result<(), Violation>) -> bool {
+        let v = match result {
+            Ok(()) => return true,
+            Err(v) => v,
+        };
+        event!(Level::ERROR, phase = "monitor", invariant = v.invariant, txid = %v.txid, detail = %v.detail,
+               votes = ?v.votes, participants = ?v.participants, state = ?self.state, "SAFETY VIOLATION: {}", v);
+        if self.on_violation == OnViolation::Halt {
+            self.running.store(false, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    ///
+    /// step(txid, input)
+    /// Feed input to the state machine of txid. An illegal transition is
+    /// logged, counted and rejected: the transaction keeps its state and
+    /// None is returned so that the caller drops the input. Otherwise returns
+    /// the actions the transition asks for.
+    ///
+    fn step(&mut self, txid: &str, input: CoordinatorInput) -> Option<Vec<CoordinatorAction>> {
+        let txn = match self.txns.get(txid) {
+            Some(txn) => txn.clone(),
+            None => CoordinatorTxn::new(self.participants.len()),
+        };
+        match txn.step(input) {
+            Ok((next, actions)) => {
+                event!(Level::DEBUG, phase = "transition", txid = %txid, from = ?txn.state, to = ?next.state, input = ?input, "state transition");
+                let state = next.state;
+                let key = transition_key(&format!("{:?}", txn.state), &input.to_string(), &format!("{:?}", state));
+                *self.transitions.entry(key).or_insert(0) += 1;
+                self.txns.insert(txid.to_string(), next);
+                if txid == self.current {
+                    self.set_state(state);
+                }
+                Some(actions)
+            },
+            Err(illegal) => {
+                self.illegal_transitions += 1;
+                let key = transition_key(&format!("{:?}", illegal.state), &input.to_string(), REJECTED);
+                *self.transitions.entry(key).or_insert(0) += 1;
+                event!(Level::ERROR, phase = "transition", txid = %txid, state = ?illegal.state, input = ?illegal.input, "rejected {}", illegal);
+                None
+            },
+        }
+    }
+
+    ///
+    /// set_state()
+    /// Move to the given state and publish the new progress snapshot
+    ///
+    fn set_state(&mut self, state: CoordinatorState) {
+        self.state = state;
+        self.publish();
+    }
+
+    ///
+    /// publish()
+    /// Copy the current counters and state into the progress snapshot
+    ///
+    fn publish(&self) {
+        if let Ok(mut progress) = self.progress.lock() {
+            progress.state = self.state;
+            progress.current = self.current.clone();
+            progress.global_commit = self.global_commit;
+            progress.global_abort = self.global_abort;
+            progress.unknown = self.unknown;
+            progress.in_flight = self.txns.values().filter(|t| t.state != CoordinatorState::SentGlobalDecision).count() as u32;
+            progress.votes = self.votes.clone();
+        }
     }
 
 
 
     pub fn receive_client_request(&mut self) {
-        let timeout_duration = Duration::from_millis(200);
+        let timeout_duration = self.idle_timeout;
         let mut start = Instant::now();
         // for (tx, message) in requests {
         loop {
@@ -161,28 +421,56 @@ impl Coordinator {
                     start = Instant::now();
                     match message.mtype {
                         MessageType::ClientRequest => {
+                            let received = self.clock.observe(message.lamport);
+                            let span = span!(Level::INFO, "transaction", role = "coordinator", txid = %message.txid, senderid = %message.senderid);
+                            let _entered = span.enter();
+                            event!(Level::INFO, phase = "request", opid = message.opid, lamport = received, "received client request");
+                            let txid = message.txid.clone();
+                            self.current = txid.clone();
+                            let actions = match self.step(&txid, CoordinatorInput::Request) {
+                                Some(actions) => actions,
+                                None => {
+                                    self.current.clear();
+                                    continue;
+                                }
+                            };
                             // Send prepare messages to all participants
                             // save the client id to send back later
                             let client_id = message.senderid.clone();
-                            self.send_prepare_message(&message.clone());
-                            // Collect votes from participants
-                            let votes = self.collect_votes();
-                            // Decide on commit or abort based on votes
-                            let decision = if votes.iter().all(|&vote| vote == MessageType::ParticipantVoteCommit) {
+                            let proposal_start = Instant::now();
+                            if actions.contains(&CoordinatorAction::SendProposals) {
+                                self.send_prepare_message(&message.clone());
+                                self.step(&txid, CoordinatorInput::ProposalsSent);
+                            }
+                            let votes_start = Instant::now();
+                            self.tracer.span("proposal_fanout", &txid, proposal_start, votes_start);
+                            // Collect votes from participants until the state
+                            // machine decides
+                            let commit = self.collect_votes(&txid);
+                            self.tracer.span("collect_votes", &txid, votes_start, Instant::now());
+                            self.stats.record("prepare_rtt", &txid, start.elapsed());
+                            let decision = if commit {
                                 MessageType::CoordinatorCommit
                             } else {
                                 MessageType::CoordinatorAbort
                             };
+                            event!(Level::INFO, phase = "decide", decision = ?decision, state = ?self.state, "decided");
+                            let mut mes = message.clone();
+                            mes.mtype = decision;
+                            // println!("Sending out decision {:?}", decision.clone());
+                            // Send the decision to all participants
+                            let fanout_start = Instant::now();
+                            if !self.send_decision_message(mes.clone()) {
+                                break;
+                            }
                             if decision == MessageType::CoordinatorCommit {
                                 self.global_commit += 1;
                             } else{
                                 self.global_abort += 1;
                             }
-                            let mut mes = message.clone();
-                            mes.mtype = decision;
-                            // println!("Sending out decision {:?}", decision.clone());
-                            // Send the decision to all participants
-                            self.send_decision_message(mes.clone());
+                            self.step(&txid, CoordinatorInput::DecisionSent);
+                            self.stats.record("decision_fanout", &txid, fanout_start.elapsed());
+                            self.tracer.span("decision_fanout", &txid, fanout_start, Instant::now());
                             // generate client result
                             let  client_decision = if decision ==  MessageType::CoordinatorCommit {
                                 MessageType::ClientResultCommit
@@ -191,10 +479,18 @@ impl Coordinator {
                             };
                             let mut client_result = message.clone();
                             client_result.mtype = client_decision;
+                            client_result.lamport = self.clock.tick();
+                            let lamport = client_result.lamport;
                             match self.clients.get(&client_id) {
-                                Some(&ref tx) => tx.send(client_result).expect("Fail to send client result."),
-                                None => println!("No client exists"),
+                                Some(tx) => tx.send(client_result).expect("Fail to send client result."),
+                                None => event!(Level::ERROR, phase = "result", "no client exists"),
                             }
+                            event!(Level::INFO, phase = "result", result = ?client_decision, lamport, "sent client result");
+                            self.stats.record("coordinator_e2e", &txid, start.elapsed());
+                            self.tracer.span("coordinator_request", &txid, start, Instant::now());
+                            self.current.clear();
+                            self.set_state(CoordinatorState::Quiescent);
+                            self.publish_metrics(false);
                         }
                         _ => continue
                     }
@@ -209,7 +505,7 @@ impl Coordinator {
                             }
                         }
                         TryRecvError::IpcError(_) =>{
-                            println!("{:?}", e);
+                            event!(Level::ERROR, role = "coordinator", error = ?e, "client channel failed");
                         }
                     }
                 },
@@ -218,18 +514,23 @@ impl Coordinator {
     }
 
     pub fn send_prepare_message(&mut self, pm: &ProtocolMessage) {
-        for (_, tx) in &self.participants {
+        self.monitor.proposed(&pm.txid);
+        for (participant, tx) in &self.participants {
             // Construct the prepare message
-            let message = ProtocolMessage::generate(MessageType::CoordinatorPropose, pm.txid.clone(), pm.senderid.clone(), pm.opid); 
+            let mut message = ProtocolMessage::generate(MessageType::CoordinatorPropose, pm.txid.clone(), pm.senderid.clone(), pm.opid);
+            message.transfer = pm.transfer;
+            message.lamport = self.clock.tick();
+            event!(Level::DEBUG, phase = "propose", participant = %participant, lamport = message.lamport, "sent proposal");
             // Send the message to the participant
             tx.send(message).expect("Failed to send prepare message");
 
         }
     }
 
-    // Collect votes from all participants
-    pub fn collect_votes(&mut self) -> Vec<MessageType> {
-        let mut votes = Vec::new();
+    // Collect votes from all participants for txid and feed them to the
+    // state machines. Stragglers for earlier transactions go to their own
+    // machines. Returns whether txid commits.
+    pub fn collect_votes(&mut self, txid: &str) -> bool {
         let timeout_duration = Duration::from_millis(200);
         let mut start = Instant::now();
 
@@ -247,18 +548,38 @@ impl Coordinator {
 
                     match message.mtype {
                         MessageType::ParticipantVoteCommit | MessageType::ParticipantVoteAbort => {
-                            votes.push(message.mtype);
                             start = Instant::now();
-                            if message.mtype == MessageType::ParticipantVoteCommit {
+                            let received = self.clock.observe(message.lamport);
+                            let checked = self.monitor.vote(&message.txid, &message.senderid, message.mtype);
+                            if !self.monitor_result(checked) {
+                                // Halting: the offending vote must not count
+                                continue;
+                            }
+                            let commit = message.mtype == MessageType::ParticipantVoteCommit;
+                            // Unknown voters get an index no transaction has room for
+                            let voter = self.voters.get(&message.senderid).cloned().unwrap_or(usize::MAX);
+                            if self.step(&message.txid, CoordinatorInput::Vote(voter, commit)).is_none() {
+                                continue;
+                            }
+                            let mix = self.votes.entry(message.senderid.clone()).or_default();
+                            if commit {
                                 self.commit+=1;
+                                mix.commit += 1;
                             } else {
                                 self.abort += 1;
+                                mix.abort += 1;
+                            }
+                            self.tracer.instant("vote_receipt", &message.txid, Instant::now(), &format!("{:?}", message.mtype));
+                            event!(Level::INFO, phase = "vote", participant = %message.senderid, vote = ?message.mtype, lamport = received, "received vote");
+                            self.publish();
+                            self.log.append(message.mtype, message.txid.clone(), message.senderid.clone(), message.opid, received);
+                            if self.txns[txid].decision.is_some() {
+                                // Every vote is in, or an abort vote settled it
+                                break;
                             }
-                            self.log.append(MessageType::ParticipantVoteCommit, message.txid.clone(), message.senderid.clone(), message.opid);
                         }
                         _ => {
-                            let mess = format!("{:?}", message);
-                            println!("{}", mess);
+                            event!(Level::WARN, phase = "vote", unexpected = ?message, "unexpected message during vote collection");
                             // eprintln!("Unexpected message type during vote collection");
                         }
                     }
@@ -266,13 +587,20 @@ impl Coordinator {
                 Err(e) => {
                     match e {
                         TryRecvError::Empty => {
-                            if votes.len() == self.participants.len(){
+                            let txn = &self.txns[txid];
+                            if txn.decision.is_some() {
                                 break;
                             }
                             if start.elapsed() >= timeout_duration {
-                                votes.push(MessageType::ParticipantVoteAbort);
-                                self.unknown += 1;
-                                self.log.append(MessageType::ParticipantVoteAbort, "None".to_string(), "None".to_string(), 0);
+                                let voted = txn.votes.iter().filter(|v| v.is_some()).count();
+                                if self.step(txid, CoordinatorInput::Timeout).is_some() {
+                                    self.unknown += 1;
+                                    self.publish();
+                                    let timed_out = self.clock.tick();
+                                    event!(Level::WARN, phase = "vote_timeout", votes = voted, lamport = timed_out, "timed out waiting for votes");
+                                    // Presumed abort: recorded as the coordinator's own abort vote
+                                    self.log.append(MessageType::ParticipantVoteAbort, txid.to_string(), "coordinator".to_string(), 0, timed_out);
+                                }
                                 break;
                             }
                             // println!("coordinator break");
@@ -284,25 +612,48 @@ impl Coordinator {
                 },
             }
         }
-        votes
+        self.txns[txid].decision == Some(true)
     }
-    // Sends the final decision message (commit or abort) to all participants.
-    pub fn send_decision_message(&mut self, decision: ProtocolMessage ) {
-        for (_, tx) in &self.participants {
+    // Logs the final decision message (commit or abort), forces it to stable
+    // storage and only then sends it to all participants. Returns false if
+    // the safety monitor vetoed the decision and the run is halting.
+    pub fn send_decision_message(&mut self, mut decision: ProtocolMessage ) -> bool {
+        let checked = self.monitor.decided(&decision.txid, decision.mtype);
+        if !self.monitor_result(checked) {
+            return false;
+        }
+        decision.lamport = self.clock.tick();
+        self.log.append(decision.mtype, decision.txid.clone(), decision.senderid.clone(), decision.opid, decision.lamport);
+        self.log.force();
+        for (participant, tx) in self.participants.iter() {
             // Send the decision message to the participant
             if let Err(e) = tx.send(decision.clone()) {
-                println!("Failed to send decision message : {}", e);
+                self.send_failures += 1;
+                event!(Level::ERROR, phase = "decision_sent", participant = %participant, error = %e, "failed to send decision");
+            } else {
+                event!(Level::DEBUG, phase = "decision_sent", participant = %participant, lamport = decision.lamport, "sent decision");
             }
         }
-        self.log.append(decision.mtype.clone(), decision.txid.clone(), decision.senderid.clone(), decision.opid);
+        true
     }
 
     pub fn send_exit_message(&mut self){
         for (_, tx) in &self.participants {
-            let message = ProtocolMessage::generate(MessageType::CoordinatorExit, "exit".to_string(), "exit".to_string(), 0);
+            let mut message = ProtocolMessage::generate(MessageType::CoordinatorExit, "exit".to_string(), "exit".to_string(), 0);
+            message.lamport = self.clock.tick();
             // Send the decision message to the participant
             if let Err(e) = tx.send(message.clone()) {
-                println!("Failed to send decision message : {}", e);
+                self.send_failures += 1;
+                event!(Level::ERROR, role = "coordinator", error = %e, "failed to send exit message");
+            }
+        }
+        // Clients waiting on a result that will never come must stop too
+        // when the run was cut short
+        if !self.running.load(Ordering::SeqCst) {
+            for tx in self.clients.values() {
+                let mut message = ProtocolMessage::generate(MessageType::CoordinatorExit, "exit".to_string(), "exit".to_string(), 0);
+                message.lamport = self.clock.tick();
+                let _ = tx.send(message);
             }
         }
     }
@@ -316,8 +667,10 @@ impl Coordinator {
     pub fn protocol(&mut self) {
 
         // TODO
+        self.stats.restart_clock();
         self.receive_client_request();
         self.send_exit_message();
+        self.publish_metrics(true);
         println!("Exit coordinator");
         // The protocol part is over, now report the status
         
@@ -0,0 +1,177 @@
+//!
+//! dashboard.rs
+//! Live terminal dashboard for "-m run --tui": committed, aborted and
+//! in-flight transactions, the vote mix of every participant, the
+//! coordinator's state and a throughput sparkline, redrawn from the
+//! coordinator's progress snapshot while the protocol runs
+//!
+extern crate ratatui;
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dashboard::ratatui::DefaultTerminal;
+use dashboard::ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use dashboard::ratatui::layout::{Constraint, Layout};
+use dashboard::ratatui::style::{Color, Style};
+use dashboard::ratatui::text::Line;
+use dashboard::ratatui::widgets::{Block, Borders, Gauge, Paragraph, Row, Sparkline, Table};
+use dashboard::ratatui::Frame;
+
+use coordinator::CoordinatorProgress;
+
+/// Time between two redraws; also the throughput sampling period
+const REFRESH_MS: u64 = 250;
+/// Number of throughput samples kept for the sparkline
+const HISTORY: usize = 240;
+
+///
+/// Dashboard
+/// Handle on the thread drawing the dashboard
+///
+pub struct Dashboard {
+    done: Arc<AtomicBool>,
+    handle: thread::JoinHandle<io::Result<()>>,
+}
+
+impl Dashboard {
+
+    ///
+    /// start()
+    /// Take over the terminal and redraw it from `progress` until stop() is
+    /// called. `total` is the number of transactions the clients will issue.
+    /// As the terminal is in raw mode, 'q' and Ctrl-C clear `running` the way
+    /// the SIGINT handler would.
+    ///
+    pub fn start(progress: Arc<Mutex<CoordinatorProgress>>, total: u32, running: Arc<AtomicBool>) -> Dashboard {
+        let done = Arc::new(AtomicBool::new(false));
+        let d = done.clone();
+        let handle = thread::spawn(move || {
+            let mut terminal = ratatui::init();
+            let result = draw_loop(&mut terminal, &progress, total, &running, &d);
+            ratatui::restore();
+            result
+        });
+        Dashboard { done, handle }
+    }
+
+    ///
+    /// stop()
+    /// Draw one last frame and give the terminal back
+    ///
+    pub fn stop(self) {
+        self.done.store(true, Ordering::SeqCst);
+        match self.handle.join() {
+            Ok(Err(e)) => error!("Dashboard failed: {:?}", e),
+            Err(_) => error!("Dashboard thread panicked"),
+            _ => (),
+        }
+    }
+}
+
+///
+/// draw_loop()
+/// Sample the progress snapshot every REFRESH_MS, redraw, and handle keys
+///
+fn draw_loop(terminal: &mut DefaultTerminal,
+             progress: &Mutex<CoordinatorProgress>,
+             total: u32,
+             running: &AtomicBool,
+             done: &AtomicBool) -> io::Result<()> {
+    let started = Instant::now();
+    let mut throughput = VecDeque::with_capacity(HISTORY);
+    let mut last_decided = 0;
+    loop {
+        let snapshot = match progress.lock() {
+            Ok(p) => p.clone(),
+            Err(_) => return Ok(()),
+        };
+        let decided = snapshot.global_commit + snapshot.global_abort;
+        if throughput.len() == HISTORY {
+            throughput.pop_front();
+        }
+        throughput.push_back((decided - last_decided) as u64 * 1000 / REFRESH_MS);
+        last_decided = decided;
+
+        let elapsed = started.elapsed();
+        terminal.draw(|frame| draw(frame, &snapshot, total, &throughput, elapsed))?;
+        if done.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(REFRESH_MS);
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            if !event::poll(left)? {
+                break;
+            }
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press && (key.code == KeyCode::Char('q') || ctrl_c) {
+                    running.store(false, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+}
+
+///
+/// draw()
+/// Lay out one frame of the dashboard
+///
+fn draw(frame: &mut Frame, p: &CoordinatorProgress, total: u32, throughput: &VecDeque<u64>, elapsed: Duration) {
+    let [progress_area, counters_area, votes_area, throughput_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Min(4),
+        Constraint::Length(6),
+    ]).areas(frame.area());
+
+    let decided = p.global_commit + p.global_abort;
+    let ratio = if total == 0 { 0.0 } else { (decided as f64 / total as f64).min(1.0) };
+    let gauge = Gauge::default()
+        .block(Block::default().borders(Borders::ALL).title(" 2PC run (q to stop) "))
+        .gauge_style(Style::default().fg(Color::Green))
+        .ratio(ratio)
+        .label(format!("{}/{} decided in {:.1}s", decided, total, elapsed.as_secs_f64()));
+    frame.render_widget(gauge, progress_area);
+
+    let counters = Paragraph::new(Line::from(format!(
+        "Committed: {:6}   Aborted: {:6}   In flight: {:2}   Unknown votes: {:6}   Coordinator: {:?} {}",
+        p.global_commit, p.global_abort, p.in_flight, p.unknown, p.state, p.current)))
+        .block(Block::default().borders(Borders::ALL).title(" Transactions "));
+    frame.render_widget(counters, counters_area);
+
+    let rows = p.votes.iter().map(|(participant, mix)| {
+        let votes = mix.commit + mix.abort;
+        let commit_pct = if votes == 0 { 0.0 } else { 100.0 * mix.commit as f64 / votes as f64 };
+        Row::new(vec![
+            participant.clone(),
+            mix.commit.to_string(),
+            mix.abort.to_string(),
+            format!("{:.1}%", commit_pct),
+        ])
+    });
+    let votes = Table::new(rows, [
+        Constraint::Length(16),
+        Constraint::Length(10),
+        Constraint::Length(10),
+        Constraint::Length(10),
+    ])
+        .header(Row::new(vec!["PARTICIPANT", "COMMIT", "ABORT", "COMMIT %"]).style(Style::default().fg(Color::Yellow)))
+        .block(Block::default().borders(Borders::ALL).title(" Votes "));
+    frame.render_widget(votes, votes_area);
+
+    // Show the most recent samples that fit in the block
+    let width = throughput_area.width.saturating_sub(2) as usize;
+    let samples: Vec<u64> = throughput.iter().skip(throughput.len().saturating_sub(width)).cloned().collect();
+    let sparkline = Sparkline::default()
+        .block(Block::default().borders(Borders::ALL)
+               .title(format!(" Throughput: {} txn/s ", samples.last().cloned().unwrap_or(0))))
+        .style(Style::default().fg(Color::Cyan))
+        .data(&samples);
+    frame.render_widget(sparkline, throughput_area);
+}
@@ -0,0 +1,599 @@
+//!
+//! explore.rs
+//! Exhaustive model checking of the protocol state machines in protocol.rs
+//! ("-m explore"). For a small configuration, by default 2 participants and
+//! 2 transactions, a breadth-first search enumerates every interleaving of
+//! message deliveries, operation outcomes and coordinator timeouts, plus up
+//! to a given number of message drops and crashes. Every reached state is
+//! checked for atomicity; since the search is breadth-first, the first
+//! violation found comes with a shortest counterexample trace. Visited
+//! states are remembered by a 128-bit fingerprint rather than in full, which
+//! keeps millions of states in memory at a negligible risk of collision.
+//! Two reductions keep the state space small: transactions nothing can
+//! happen to any more are reset to one value, and what participants still
+//! do on decided transactions is not interleaved with everything else.
+//!
+use std::collections::{HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use coordinator::CoordinatorState;
+use participant::ParticipantState;
+use protocol::{CoordinatorAction, CoordinatorInput, CoordinatorTxn, IllegalTransition};
+use protocol::{ParticipantAction, ParticipantInput};
+use tpcoptions::TPCOptions;
+
+///
+/// ModelConfig
+/// Size of the model and how much may go wrong in it
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModelConfig {
+    pub participants: usize,
+    pub txns: usize,
+    pub drops: u32,         // Messages the network may lose
+    pub crashes: u32,       // Processes that may crash (and stay down)
+    pub max_states: usize,  // Give up after exploring this many states
+}
+
+impl ModelConfig {
+
+    ///
+    /// parse(spec)
+    /// Parses "p=2,t=2,drops=1,crashes=1,states=1000000"; missing keys keep
+    /// those defaults, which a debug build checks in seconds
+    ///
+    pub fn parse(spec: &str) -> Result<ModelConfig, String> {
+        let mut config = ModelConfig { participants: 2, txns: 2, drops: 1, crashes: 1, max_states: 1_000_000 };
+        for part in spec.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let eq = part.find('=').ok_or(format!("Model setting \"{}\" is not of the form key=value", part))?;
+            let (key, value) = (part[..eq].trim(), part[eq + 1..].trim());
+            let bad = |e: &dyn fmt::Display| format!("Invalid value \"{}\" for model setting \"{}\": {}", value, key, e);
+            match key {
+                "p" => config.participants = value.parse().map_err(|e| bad(&e))?,
+                "t" => config.txns = value.parse().map_err(|e| bad(&e))?,
+                "drops" => config.drops = value.parse().map_err(|e| bad(&e))?,
+                "crashes" => config.crashes = value.parse().map_err(|e| bad(&e))?,
+                "states" => config.max_states = value.parse().map_err(|e| bad(&e))?,
+                _ => return Err(format!("Unknown model setting \"{}\"", key)),
+            }
+        }
+        if config.participants == 0 || config.txns == 0 {
+            return Err("The model needs at least one participant and one transaction".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// The coordinator's transition function, CoordinatorTxn::step outside of tests
+type CoordinatorStep = fn(&CoordinatorTxn, CoordinatorInput)
+    -> Result<(CoordinatorTxn, Vec<CoordinatorAction>), IllegalTransition<CoordinatorState, CoordinatorInput>>;
+
+///
+/// Message
+/// A protocol message in flight
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Message {
+    Propose { txn: usize, to: usize },
+    Vote { txn: usize, from: usize, commit: bool },
+    Decision { txn: usize, to: usize, commit: bool },
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Message::Propose { txn, to } => write!(f, "Propose(txn_{}) to participant_{}", txn, to),
+            Message::Vote { txn, from, commit } =>
+                write!(f, "Vote{}(txn_{}) from participant_{}", if commit { "Commit" } else { "Abort" }, txn, from),
+            Message::Decision { txn, to, commit } =>
+                write!(f, "{}(txn_{}) to participant_{}", if commit { "Commit" } else { "Abort" }, txn, to),
+        }
+    }
+}
+
+///
+/// Step
+/// One transition of the whole system
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    Request(usize),                         // The coordinator takes the next client request
+    Timeout(usize),                         // The coordinator stops waiting for votes
+    Deliver(Message),
+    Drop(Message),
+    Operate { participant: usize, txn: usize, ok: bool },
+    SendVote { participant: usize, txn: usize },
+    CrashCoordinator,
+    CrashParticipant(usize),
+}
+
+impl Step {
+
+    ///
+    /// local()
+    /// The participant and transaction a step is about, if it concerns just
+    /// one participant's side of one transaction
+    ///
+    fn local(&self) -> Option<(usize, usize)> {
+        match *self {
+            Step::Deliver(Message::Propose { txn, to }) | Step::Deliver(Message::Decision { txn, to, .. })
+                | Step::Drop(Message::Propose { txn, to }) | Step::Drop(Message::Decision { txn, to, .. }) => Some((to, txn)),
+            Step::Operate { participant, txn, .. } | Step::SendVote { participant, txn } => Some((participant, txn)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Step::Request(txn) => write!(f, "coordinator receives the request for txn_{} and proposes it", txn),
+            Step::Timeout(txn) => write!(f, "coordinator times out waiting for votes on txn_{}", txn),
+            Step::Deliver(m) => write!(f, "deliver {}", m),
+            Step::Drop(m) => write!(f, "network drops {}", m),
+            Step::Operate { participant, txn, ok } =>
+                write!(f, "participant_{} {} the operation of txn_{}", participant, if ok { "performs" } else { "fails" }, txn),
+            Step::SendVote { participant, txn } => write!(f, "participant_{} sends its logged commit vote on txn_{}", participant, txn),
+            Step::CrashCoordinator => write!(f, "coordinator crashes"),
+            Step::CrashParticipant(p) => write!(f, "participant_{} crashes", p),
+        }
+    }
+}
+
+///
+/// World
+/// The state of every process and of the network
+///
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct World {
+    coordinator: Vec<CoordinatorTxn>,           // By txn
+    coordinator_up: bool,
+    participants: Vec<Vec<ParticipantState>>,   // By participant, then txn
+    logged_votes: Vec<Vec<Option<bool>>>,       // By participant, then txn
+    applied: Vec<Vec<Option<bool>>>,            // Outcome each participant applied
+    up: Vec<bool>,
+    network: Vec<Message>,                      // Sorted, so equal multisets hash alike
+    drops: u32,
+    crashes: u32,
+}
+
+impl World {
+
+    fn new(config: &ModelConfig) -> World {
+        World {
+            coordinator: vec![CoordinatorTxn::new(config.participants); config.txns],
+            coordinator_up: true,
+            participants: vec![vec![ParticipantState::Quiescent; config.txns]; config.participants],
+            logged_votes: vec![vec![None; config.txns]; config.participants],
+            applied: vec![vec![None; config.txns]; config.participants],
+            up: vec![true; config.participants],
+            network: Vec::new(),
+            drops: 0,
+            crashes: 0,
+        }
+    }
+
+    ///
+    /// fingerprint()
+    /// 128-bit hash identifying this state
+    ///
+    fn fingerprint(&self) -> u128 {
+        let mut low = DefaultHasher::new();
+        self.hash(&mut low);
+        let mut high = DefaultHasher::new();
+        (0xfeed_u16, self).hash(&mut high);
+        (high.finish() as u128) << 64 | low.finish() as u128
+    }
+
+    fn send(&mut self, m: Message) {
+        let i = self.network.binary_search(&m).unwrap_or_else(|i| i);
+        self.network.insert(i, m);
+    }
+
+    fn take(&mut self, m: Message) {
+        if let Ok(i) = self.network.binary_search(&m) {
+            self.network.remove(i);
+        }
+    }
+
+    ///
+    /// current_txn()
+    /// The coordinator handles one request at a time: the first transaction
+    /// it has not decided yet
+    ///
+    fn current_txn(&self) -> Option<usize> {
+        self.coordinator.iter().position(|t| t.state != CoordinatorState::SentGlobalDecision)
+    }
+
+    ///
+    /// coordinator_step(txn, input, step)
+    /// Feeds the coordinator's state machine and carries out its actions
+    ///
+    fn coordinator_step(&mut self, txn: usize, input: CoordinatorInput, step: CoordinatorStep) -> Result<(), String> {
+        let (next, actions) = step(&self.coordinator[txn], input)
+            .map_err(|e| format!("coordinator on txn_{}: {}", txn, e))?;
+        self.coordinator[txn] = next;
+        for action in actions {
+            match action {
+                CoordinatorAction::SendProposals => {
+                    for to in 0..self.up.len() {
+                        self.send(Message::Propose { txn, to });
+                    }
+                    self.coordinator_step(txn, CoordinatorInput::ProposalsSent, step)?;
+                },
+                CoordinatorAction::Decide(commit) => {
+                    for to in 0..self.up.len() {
+                        self.send(Message::Decision { txn, to, commit });
+                    }
+                    self.coordinator_step(txn, CoordinatorInput::DecisionSent, step)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// participant_step(p, txn, input)
+    /// Feeds a participant's state machine and carries out its actions
+    ///
+    fn participant_step(&mut self, p: usize, txn: usize, input: ParticipantInput) -> Result<(), String> {
+        let (next, actions) = self.participants[p][txn].step(input)
+            .map_err(|e| format!("participant_{} on txn_{}: {}", p, txn, e))?;
+        self.participants[p][txn] = next;
+        for action in actions {
+            match action {
+                ParticipantAction::LogVote(commit) => self.logged_votes[p][txn] = Some(commit),
+                ParticipantAction::SendVote(commit) => self.send(Message::Vote { txn, from: p, commit }),
+                ParticipantAction::Apply(commit) => self.applied[p][txn] = Some(commit),
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// steps(config, reduce)
+    /// Every transition enabled in this state. With reduce, if a participant
+    /// that is up has steps left on a transaction the coordinator decided,
+    /// only those (and drops of its messages about it) are taken: nothing
+    /// about the transaction is sent any more, so they commute with every
+    /// other step, and taking them before a crash of the participant only
+    /// adds outcomes to check (of the two operation outcomes, the failed one
+    /// keeps a missing commit vote missing).
+    ///
+    fn steps(&self, config: &ModelConfig, reduce: bool) -> Vec<Step> {
+        let mut steps = Vec::new();
+        if self.coordinator_up {
+            if let Some(txn) = self.current_txn() {
+                match self.coordinator[txn].state {
+                    CoordinatorState::Quiescent => steps.push(Step::Request(txn)),
+                    CoordinatorState::ProposalSent => steps.push(Step::Timeout(txn)),
+                    _ => (),
+                }
+            }
+        }
+        let mut messages = self.network.clone();
+        messages.dedup();
+        for m in messages {
+            let receiver_up = match m {
+                Message::Propose { to, .. } | Message::Decision { to, .. } => self.up[to],
+                Message::Vote { .. } => self.coordinator_up,
+            };
+            if receiver_up {
+                steps.push(Step::Deliver(m));
+            }
+            if self.drops < config.drops {
+                steps.push(Step::Drop(m));
+            }
+        }
+        for (p, txns) in self.participants.iter().enumerate().filter(|(p, _)| self.up[*p]) {
+            for (txn, state) in txns.iter().enumerate() {
+                match *state {
+                    ParticipantState::ReceivedP1 => {
+                        steps.push(Step::Operate { participant: p, txn, ok: true });
+                        steps.push(Step::Operate { participant: p, txn, ok: false });
+                    },
+                    ParticipantState::VotedCommit => steps.push(Step::SendVote { participant: p, txn }),
+                    _ => (),
+                }
+            }
+        }
+        if self.crashes < config.crashes {
+            if self.coordinator_up {
+                steps.push(Step::CrashCoordinator);
+            }
+            steps.extend((0..self.up.len()).filter(|p| self.up[*p]).map(Step::CrashParticipant));
+        }
+        if reduce {
+            let decided = |&(_, txn): &(usize, usize)| self.coordinator[txn].state == CoordinatorState::SentGlobalDecision;
+            let first = steps.iter().filter(|s| !matches!(**s, Step::Drop(_)))
+                .filter_map(Step::local).find(decided);
+            if let Some(local) = first {
+                steps.retain(|s| s.local() == Some(local));
+            }
+        }
+        steps
+    }
+
+    ///
+    /// apply(step, coordinator)
+    /// The state after taking step, or the illegal transition it caused
+    ///
+    fn apply(&self, step: Step, coordinator: CoordinatorStep) -> Result<World, String> {
+        let mut w = self.clone();
+        match step {
+            Step::Request(txn) => w.coordinator_step(txn, CoordinatorInput::Request, coordinator)?,
+            Step::Timeout(txn) => w.coordinator_step(txn, CoordinatorInput::Timeout, coordinator)?,
+            Step::Deliver(m) => {
+                w.take(m);
+                match m {
+                    Message::Propose { txn, to } => w.participant_step(to, txn, ParticipantInput::Propose)?,
+                    Message::Vote { txn, from, commit } => w.coordinator_step(txn, CoordinatorInput::Vote(from, commit), coordinator)?,
+                    Message::Decision { txn, to, commit } => w.participant_step(to, txn, ParticipantInput::Decision(commit))?,
+                }
+            },
+            Step::Drop(m) => {
+                w.take(m);
+                w.drops += 1;
+            },
+            Step::Operate { participant, txn, ok } => w.participant_step(participant, txn, ParticipantInput::Vote(ok))?,
+            Step::SendVote { participant, txn } => w.participant_step(participant, txn, ParticipantInput::VoteLogged)?,
+            Step::CrashCoordinator => {
+                w.coordinator_up = false;
+                w.crashes += 1;
+            },
+            Step::CrashParticipant(p) => {
+                w.up[p] = false;
+                w.crashes += 1;
+            },
+        }
+        Ok(w)
+    }
+
+    ///
+    /// finished(txn)
+    /// Nothing can happen to txn any more: the coordinator decided it, no
+    /// message about it is in flight and no participant that is up has a
+    /// step of its own left to take on it
+    ///
+    fn finished(&self, txn: usize) -> bool {
+        self.coordinator[txn].state == CoordinatorState::SentGlobalDecision
+            && self.network.iter().all(|m| match *m {
+                Message::Propose { txn: t, .. } | Message::Vote { txn: t, .. } | Message::Decision { txn: t, .. } => t != txn,
+            })
+            && self.participants.iter().zip(&self.up).all(|(txns, up)| {
+                !up || (txns[txn] != ParticipantState::ReceivedP1 && txns[txn] != ParticipantState::VotedCommit)
+            })
+    }
+
+    ///
+    /// canonical()
+    /// This state with every finished transaction reset to the same value,
+    /// so that states which differ only in how earlier transactions ended
+    /// are explored once. Their atomicity is checked before they are reset.
+    ///
+    fn canonical(mut self) -> World {
+        for txn in 0..self.coordinator.len() {
+            if self.finished(txn) {
+                self.coordinator[txn] = CoordinatorTxn {
+                    state: CoordinatorState::SentGlobalDecision,
+                    votes: vec![None; self.up.len()],
+                    decision: None,
+                };
+                for p in 0..self.up.len() {
+                    self.participants[p][txn] = ParticipantState::Quiescent;
+                    self.logged_votes[p][txn] = None;
+                    self.applied[p][txn] = None;
+                }
+            }
+        }
+        self
+    }
+
+    ///
+    /// check_atomicity()
+    /// Every participant that applied an outcome applied the coordinator's
+    /// decision, and a transaction commits only if every participant logged
+    /// a commit vote
+    ///
+    fn check_atomicity(&self) -> Result<(), String> {
+        for (txn, coordinator) in self.coordinator.iter().enumerate() {
+            let mut outcomes: Vec<(String, bool)> = self.applied.iter().enumerate()
+                .filter_map(|(p, applied)| applied[txn].map(|c| (format!("participant_{}", p), c)))
+                .collect();
+            if let Some(decision) = coordinator.decision {
+                outcomes.insert(0, ("coordinator".to_string(), decision));
+            }
+            if let Some((who, commit)) = outcomes.first() {
+                if let Some((other, _)) = outcomes.iter().find(|(_, c)| c != commit) {
+                    return Err(format!("{} {} txn_{} but {} {} it", who, outcome(*commit), txn, other, outcome(!*commit)));
+                }
+                if *commit {
+                    if let Some(p) = self.logged_votes.iter().position(|v| v[txn] != Some(true)) {
+                        return Err(format!("txn_{} committed without a commit vote from participant_{}", txn, p));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn outcome(commit: bool) -> &'static str {
+    if commit { "committed" } else { "aborted" }
+}
+
+///
+/// Verdict
+/// What the explorer found
+///
+#[derive(Debug)]
+pub struct Verdict {
+    pub states: usize,
+    pub complete: bool,                     // False if max_states cut the search short
+    pub violation: Option<(String, Vec<String>)>,   // What went wrong, and the steps leading there
+}
+
+///
+/// explore(config)
+/// Breadth-first search over all reachable states
+///
+pub fn explore(config: &ModelConfig) -> Verdict {
+    search(config, CoordinatorTxn::step)
+}
+
+///
+/// search(config, coordinator)
+/// explore() with the given coordinator transition function. A violation
+/// found in the reduced state space is looked for again in the full one,
+/// where breadth-first search stops at a shortest trace to it.
+///
+fn search(config: &ModelConfig, coordinator: CoordinatorStep) -> Verdict {
+    let verdict = bfs(config, coordinator, true);
+    if verdict.violation.is_none() {
+        return verdict;
+    }
+    match bfs(config, coordinator, false) {
+        Verdict { violation: None, .. } => verdict,
+        shortest => shortest,
+    }
+}
+
+///
+/// bfs(config, coordinator, reduce)
+/// Breadth-first search, taking only one participant's steps on a decided
+/// transaction where it has some if reduce is set
+///
+fn bfs(config: &ModelConfig, coordinator: CoordinatorStep, reduce: bool) -> Verdict {
+    let start = World::new(config);
+    let mut seen = HashSet::new();
+    let mut parents: Vec<Option<(usize, Step)>> = vec![None];
+    let mut queue = VecDeque::new();
+    seen.insert(start.fingerprint());
+    queue.push_back((0, start));
+
+    let trace = |parents: &Vec<Option<(usize, Step)>>, mut i: usize, last: Option<Step>| {
+        let mut steps: Vec<String> = last.iter().map(|s| s.to_string()).collect();
+        while let Some((parent, step)) = parents[i] {
+            steps.push(step.to_string());
+            i = parent;
+        }
+        steps.reverse();
+        steps
+    };
+
+    while let Some((i, world)) = queue.pop_front() {
+        for step in world.steps(config, reduce) {
+            let next = match world.apply(step, coordinator) {
+                Ok(next) => next,
+                Err(e) => return Verdict { states: parents.len(), complete: false, violation: Some((e, trace(&parents, i, Some(step)))) },
+            };
+            if let Err(e) = next.check_atomicity() {
+                return Verdict { states: parents.len() + 1, complete: false, violation: Some((e, trace(&parents, i, Some(step)))) };
+            }
+            let next = next.canonical();
+            if !seen.insert(next.fingerprint()) {
+                continue;
+            }
+            let j = parents.len();
+            parents.push(Some((i, step)));
+            if j >= config.max_states {
+                return Verdict { states: j, complete: false, violation: None };
+            }
+            queue.push_back((j, next));
+        }
+    }
+    Verdict { states: parents.len(), complete: true, violation: None }
+}
+
+///
+/// run_explore(opts)
+/// "-m explore": model checks the configuration given by --model and prints
+/// either the number of states in which atomicity holds or a shortest
+/// counterexample. Returns false on a violation.
+///
+pub fn run_explore(opts: &TPCOptions) -> bool {
+    let config = ModelConfig::parse(&opts.model).unwrap();
+    let verdict = explore(&config);
+    let setup = format!("{} participants, {} transactions, up to {} drops and {} crashes",
+                        config.participants, config.txns, config.drops, config.crashes);
+    match verdict.violation {
+        Some((violation, steps)) => {
+            println!("VIOLATION after {} steps ({}): {}", steps.len(), setup, violation);
+            for (n, step) in steps.iter().enumerate() {
+                println!("{:4}. {}", n + 1, step);
+            }
+            false
+        },
+        None if verdict.complete => {
+            println!("OK: atomicity holds in all {} reachable states ({})", verdict.states, setup);
+            true
+        },
+        None => {
+            println!("INCOMPLETE: atomicity holds in the first {} states ({}); raise states= to search further",
+                     verdict.states, setup);
+            true
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A broken coordinator that commits on the first commit vote instead of
+    /// waiting for all of them
+    fn commit_on_first_vote(txn: &CoordinatorTxn, input: CoordinatorInput)
+        -> Result<(CoordinatorTxn, Vec<CoordinatorAction>), IllegalTransition<CoordinatorState, CoordinatorInput>> {
+        let (mut next, mut actions) = txn.step(input)?;
+        if txn.state == CoordinatorState::ProposalSent && input == CoordinatorInput::Vote(0, true) && actions.is_empty() {
+            next.state = CoordinatorState::ReceivedVotesCommit;
+            next.decision = Some(true);
+            actions.push(CoordinatorAction::Decide(true));
+        }
+        Ok((next, actions))
+    }
+
+    #[test]
+    fn default_model_is_safe_and_complete() {
+        let config = ModelConfig::parse("").unwrap();
+        assert_eq!((config.participants, config.txns), (2, 2));
+        let verdict = explore(&config);
+        assert!(verdict.complete);
+        assert!(verdict.violation.is_none());
+        assert!(verdict.states > 1000 && verdict.states < config.max_states);
+    }
+
+    #[test]
+    fn reduced_search_still_finds_a_broken_commit_rule() {
+        let config = ModelConfig::parse("p=2,t=2").unwrap();
+        let reduced = bfs(&config, commit_on_first_vote, true);
+        let (violation, _) = reduced.violation.expect("no violation found");
+        assert!(violation.contains("committed without a commit vote"), "{}", violation);
+        assert_eq!(search(&config, commit_on_first_vote).violation.unwrap().1.len(), 5);
+    }
+
+    #[test]
+    fn broken_commit_rule_yields_shortest_counterexample() {
+        let config = ModelConfig::parse("p=2,t=1,drops=1,crashes=1").unwrap();
+        let (violation, steps) = search(&config, commit_on_first_vote).violation.expect("no violation found");
+        assert_eq!(violation, "txn_0 committed without a commit vote from participant_1");
+        assert_eq!(steps, vec![
+            "coordinator receives the request for txn_0 and proposes it",
+            "deliver Propose(txn_0) to participant_0",
+            "participant_0 performs the operation of txn_0",
+            "participant_0 sends its logged commit vote on txn_0",
+            "deliver VoteCommit(txn_0) from participant_0",
+        ]);
+    }
+
+    #[test]
+    fn model_settings_are_validated() {
+        let config = ModelConfig::parse("p=3, drops=0").unwrap();
+        assert_eq!((config.participants, config.txns, config.drops, config.crashes), (3, 2, 0, 1));
+        assert!(ModelConfig::parse("p=0").is_err());
+        assert!(ModelConfig::parse("t").is_err());
+        assert!(ModelConfig::parse("q=1").is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//!
+//! graph.rs
+//! Graphviz export of the coordinator and participant state machines for
+//! "graph" mode. The edges are enumerated from the transition functions in
+//! protocol.rs and annotated with how often the roles took each of them in
+//! the run whose stats reports are in log_path. Transitions that were never
+//! taken are dashed; inputs the roles rejected as illegal show up as red
+//! edges into a Rejected node.
+//!
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fmt::Write;
+
+use coordinator::CoordinatorState;
+use participant::ParticipantState;
+use protocol::{transition_key, CoordinatorInput, CoordinatorTxn, ParticipantInput, REJECTED};
+use stats;
+use stats::StatsReport;
+use tpcoptions::TPCOptions;
+
+///
+/// Machine
+/// States and transitions (from, input, to) of one role, and how often the
+/// run took each transition, by protocol::transition_key
+///
+#[derive(Debug)]
+pub struct Machine {
+    pub name: &'static str,
+    pub states: Vec<String>,
+    pub edges: BTreeSet<(String, String, String)>,
+    pub counts: BTreeMap<String, u64>,
+}
+
+impl Machine {
+
+    ///
+    /// coordinator()
+    /// Every transition a coordinator transaction can take, found by
+    /// stepping through all reachable CoordinatorTxns of two participants
+    /// (enough for a vote to both leave the votes incomplete and complete
+    /// them). Votes are told apart by outcome only.
+    ///
+    pub fn coordinator() -> Machine {
+        let num_participants = 2;
+        let mut inputs = vec![
+            CoordinatorInput::Request,
+            CoordinatorInput::ProposalsSent,
+            CoordinatorInput::Timeout,
+            CoordinatorInput::DecisionSent,
+        ];
+        for i in 0..num_participants {
+            inputs.push(CoordinatorInput::Vote(i, true));
+            inputs.push(CoordinatorInput::Vote(i, false));
+        }
+
+        let mut edges = BTreeSet::new();
+        let initial = CoordinatorTxn::new(num_participants);
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        seen.insert(initial.clone());
+        queue.push_back(initial);
+        while let Some(txn) = queue.pop_front() {
+            for input in &inputs {
+                if let Ok((next, _)) = txn.step(*input) {
+                    edges.insert((format!("{:?}", txn.state), input.to_string(), format!("{:?}", next.state)));
+                    if seen.insert(next.clone()) {
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        Machine {
+            name: "coordinator",
+            states: CoordinatorState::ALL.iter().map(|s| format!("{:?}", s)).collect(),
+            edges,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    ///
+    /// participant()
+    /// Every transition a participant transaction can take
+    ///
+    pub fn participant() -> Machine {
+        let inputs = [
+            ParticipantInput::Propose,
+            ParticipantInput::Vote(true),
+            ParticipantInput::Vote(false),
+            ParticipantInput::VoteLogged,
+            ParticipantInput::Decision(true),
+            ParticipantInput::Decision(false),
+        ];
+        let mut edges = BTreeSet::new();
+        for state in ParticipantState::ALL.iter() {
+            for input in &inputs {
+                if let Ok((next, _)) = state.step(*input) {
+                    edges.insert((format!("{:?}", state), input.to_string(), format!("{:?}", next)));
+                }
+            }
+        }
+        Machine {
+            name: "participant",
+            states: ParticipantState::ALL.iter().map(|s| format!("{:?}", s)).collect(),
+            edges,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    ///
+    /// observe(report)
+    /// Adds the transitions counted in a role's stats report. Transitions
+    /// the enumeration did not find (rejected inputs, or reports of another
+    /// version of the protocol) become edges of their own.
+    ///
+    pub fn observe(&mut self, report: &StatsReport) {
+        for (key, count) in &report.transitions {
+            if let Some(edge) = parse_key(key) {
+                self.edges.insert(edge);
+                *self.counts.entry(key.clone()).or_insert(0) += count;
+            } else {
+                warn!("Ignoring malformed transition {:?} in the report of {}", key, report.id);
+            }
+        }
+    }
+
+    ///
+    /// unexercised()
+    /// Transitions the run never took
+    ///
+    pub fn unexercised(&self) -> Vec<&(String, String, String)> {
+        self.edges.iter()
+            .filter(|(from, input, to)| !self.counts.contains_key(&transition_key(from, input, to)))
+            .collect()
+    }
+
+    ///
+    /// write_dot(out)
+    /// The machine as a DOT cluster. Node names are prefixed with the
+    /// machine's name so that both machines fit in one graph.
+    ///
+    fn write_dot(&self, out: &mut String) {
+        let node = |state: &str| format!("\"{}_{}\"", self.name, state);
+        let _ = writeln!(out, "  subgraph cluster_{} {{", self.name);
+        let _ = writeln!(out, "    label=\"{}\";", self.name);
+        for (i, state) in self.states.iter().enumerate() {
+            // The first state is the initial one
+            let style = if i == 0 { ", penwidth=2" } else { "" };
+            let _ = writeln!(out, "    {} [label=\"{}\"{}];", node(state), state, style);
+        }
+        if self.edges.iter().any(|(_, _, to)| to == REJECTED) {
+            let _ = writeln!(out, "    {} [label=\"{}\", shape=octagon, color=red, fontcolor=red];", node(REJECTED), REJECTED);
+        }
+        for (from, input, to) in &self.edges {
+            let count = self.counts.get(&transition_key(from, input, to)).cloned().unwrap_or(0);
+            let style = if to == REJECTED {
+                format!("color=red, fontcolor=red, penwidth={:.1}", penwidth(count))
+            } else if count == 0 {
+                "style=dashed, color=gray, fontcolor=gray".to_string()
+            } else {
+                format!("penwidth={:.1}", penwidth(count))
+            };
+            let _ = writeln!(out, "    {} -> {} [label=\"{}\\n{}\", {}];", node(from), node(to), input, count, style);
+        }
+        let _ = writeln!(out, "  }}");
+    }
+}
+
+///
+/// parse_key(key)
+/// The (from, input, to) of a protocol::transition_key
+///
+fn parse_key(key: &str) -> Option<(String, String, String)> {
+    let (from, rest) = key.split_once(" --")?;
+    let (input, to) = rest.rsplit_once("--> ")?;
+    Some((from.to_string(), input.to_string(), to.to_string()))
+}
+
+///
+/// penwidth(count)
+/// Edges get thicker with the order of magnitude of their count
+///
+fn penwidth(count: u64) -> f64 {
+    1.0 + (count.max(1) as f64).log10()
+}
+
+///
+/// to_dot(machines)
+/// One digraph with a cluster per machine
+///
+pub fn to_dot(machines: &[Machine]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "digraph tpc {{");
+    let _ = writeln!(out, "  rankdir=LR;");
+    let _ = writeln!(out, "  node [shape=box, style=rounded];");
+    for machine in machines {
+        machine.write_dot(&mut out);
+    }
+    let _ = writeln!(out, "}}");
+    out
+}
+
+///
+/// print_graph(opts)
+///
+/// Prints the DOT of both state machines to stdout, annotated with the
+/// transitions of the coordinator and the opts.num_participants participants
+/// whose stats reports are in opts.log_path. Missing reports only leave
+/// their counts out, so the graph of the bare protocol needs no run.
+///
+pub fn print_graph(opts: &TPCOptions) {
+    let mut coordinator = Machine::coordinator();
+    let mut participant = Machine::participant();
+    let mut ids = vec!["coordinator".to_string()];
+    ids.extend((0..opts.num_participants).map(|i| format!("participant_{}", i)));
+    for id in ids {
+        match StatsReport::from_file(&stats::report_path(&opts.log_path, &id)) {
+            Ok(report) if id == "coordinator" => coordinator.observe(&report),
+            Ok(report) => participant.observe(&report),
+            Err(e) => warn!("No stats report of {} in {}: {}", id, opts.log_path, e),
+        }
+    }
+    for machine in [&coordinator, &participant].iter() {
+        for (from, input, to) in machine.unexercised() {
+            info!("{}: {} --{}--> {} never taken", machine.name, from, input, to);
+        }
+    }
+    print!("{}", to_dot(&[coordinator, participant]));
+}
@@ -0,0 +1,295 @@
+//!
+//! harness.rs
+//! In-process harness for property-based tests of the protocol. Runs the
+//! coordinator, participants and clients of one configuration on threads
+//! of this process, wired with the same IPC channels as "run" mode, and
+//! checks the resulting logs with the checker. Participants can crash: a
+//! proxy between the coordinator and the participant stops delivering
+//! after a given number of messages and closes the participant's channel.
+//!
+//! Seeds fix every random choice of the roles (operation outcomes, message
+//! drops, transfers), but not the thread schedule, so a failing case
+//! reproduces its random choices exactly and its timing only roughly.
+//!
+extern crate proptest;
+
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use ipc_channel::ipc::IpcReceiver as Receiver;
+use ipc_channel::ipc::IpcSender as Sender;
+use ipc_channel::ipc::channel;
+
+use bank;
+use bank::{Bank, BankConfig};
+use checker;
+use client::Client;
+use coordinator::Coordinator;
+use message::MessageType;
+use message::ProtocolMessage;
+use monitor::OnViolation;
+use oplog::LogConfig;
+use participant::Participant;
+use stats::StatsReport;
+use workload::Arrival;
+
+/// How long the coordinator waits for a request before assuming the clients
+/// are done. Closed-loop clients without think time send their next request
+/// right away, so this only has to outlast scheduling hiccups.
+const IDLE_TIMEOUT_MS: u64 = 500;
+
+/// Numbers the log directories of the runs of this process
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// Crash
+/// Participant that stops after receiving `after` messages
+///
+#[derive(Clone, Copy, Debug)]
+pub struct Crash {
+    pub participant: u32,
+    pub after: u32,
+}
+
+///
+/// Config
+/// One system to run
+///
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub participants: u32,
+    pub clients: u32,
+    pub requests: u32,                      // Per client
+    pub send_success_probability: f64,
+    pub operation_success_probability: f64,
+    pub crash: Option<Crash>,
+    pub bank: bool,                         // Bank workload instead of plain operations
+    pub seed: u64,
+}
+
+///
+/// Outcome
+/// Where a run left its logs, and every role's report
+///
+#[derive(Debug)]
+pub struct Outcome {
+    pub log_path: String,
+    pub coordinator: StatsReport,
+    pub participants: Vec<StatsReport>,
+    pub clients: Vec<StatsReport>,
+}
+
+///
+/// run(config)
+/// Runs config to completion in a fresh log directory
+///
+pub fn run(config: &Config) -> Outcome {
+    let log_path = std::env::temp_dir()
+        .join(format!("tpc_harness_{}_{}", std::process::id(), RUNS.fetch_add(1, Ordering::SeqCst)))
+        .to_string_lossy()
+        .into_owned();
+    let _ = fs::remove_dir_all(&log_path);
+    fs::create_dir_all(&log_path).expect("Failed to create log directory");
+
+    let running = Arc::new(AtomicBool::new(true));
+    let (tx_coor_client, rx_coor_client): (Sender<ProtocolMessage>, Receiver<ProtocolMessage>) = channel().unwrap();
+    let (tx_coor_part, rx_coor_part): (Sender<ProtocolMessage>, Receiver<ProtocolMessage>) = channel().unwrap();
+    let mut coordinator = Coordinator::new(format!("{}//coordinator.log", log_path), LogConfig::default(), &running,
+                                           config.requests, rx_coor_client, rx_coor_part,
+                                           Duration::from_millis(IDLE_TIMEOUT_MS), OnViolation::Log);
+    let bank = if config.bank {
+        Some(BankConfig { num_accounts: 2 * config.participants, initial_balance: 100, num_participants: config.participants })
+    } else {
+        None
+    };
+
+    let mut participants = Vec::new();
+    for i in 0..config.participants {
+        let id = format!("participant_{}", i);
+        let (tx, rx) = channel().unwrap();
+        let rx = match config.crash {
+            Some(crash) if crash.participant == i => crash_after(rx, crash.after),
+            _ => rx,
+        };
+        let mut participant = Participant::new(id.clone(), format!("{}//{}.log", log_path, id), LogConfig::default(),
+                                               running.clone(), config.send_success_probability,
+                                               config.operation_success_probability,
+                                               tx_coor_part.clone(), rx, bank.map(|b| Bank::new(b, i)));
+        participant.seed(config.seed.wrapping_add(i as u64));
+        coordinator.participant_join(&id, tx);
+        let balances_path = bank::balances_path(&log_path, &id);
+        participants.push(thread::spawn(move || {
+            participant.protocol();
+            if let Some(snapshot) = participant.bank_snapshot() {
+                snapshot.write_json(&balances_path).expect("Failed to write balances");
+            }
+            participant.stats_report()
+        }));
+    }
+
+    let mut clients = Vec::new();
+    for i in 0..config.clients {
+        let id = format!("client_{}", i);
+        let (tx, rx) = channel().unwrap();
+        let mut client = Client::new(id.clone(), running.clone(), tx_coor_client.clone(), rx, config.requests,
+                                     Arrival::ThinkTime(Duration::from_millis(0)), bank);
+        client.seed(config.seed.wrapping_add((config.participants + i) as u64));
+        coordinator.client_join(&id, tx);
+        let requests = config.requests;
+        clients.push(thread::spawn(move || {
+            client.protocol(requests);
+            client.stats_report()
+        }));
+    }
+
+    coordinator.protocol();
+    Outcome {
+        log_path,
+        coordinator: coordinator.stats_report(),
+        participants: participants.into_iter().map(|h| h.join().expect("Participant panicked")).collect(),
+        clients: clients.into_iter().map(|h| h.join().expect("Client panicked")).collect(),
+    }
+}
+
+///
+/// crash_after(rx, after)
+/// Receiver that delivers the first `after` messages of rx and then closes,
+/// as if its participant had crashed. The rest of rx is drained so that the
+/// coordinator can keep sending.
+///
+fn crash_after(rx: Receiver<ProtocolMessage>, after: u32) -> Receiver<ProtocolMessage> {
+    let (tx, proxied) = channel().unwrap();
+    thread::spawn(move || {
+        let mut tx = if after > 0 { Some(tx) } else { None };
+        let mut delivered = 0;
+        while let Ok(message) = rx.recv() {
+            let exit = message.mtype == MessageType::CoordinatorExit;
+            if let Some(t) = tx.take() {
+                let _ = t.send(message);
+                delivered += 1;
+                if delivered < after {
+                    tx = Some(t);
+                }
+            }
+            if exit {
+                break;
+            }
+        }
+    });
+    proxied
+}
+
+///
+/// counter(report, name)
+/// A counter of a role's report, 0 if the role has no such counter
+///
+fn counter(report: &StatsReport, name: &str) -> u64 {
+    report.counters.get(name).cloned().unwrap_or(0)
+}
+
+///
+/// check(config, outcome)
+/// Panics unless the run satisfies the checker's invariants, neither the
+/// safety monitor nor the state machines objected to anything, and every
+/// request was decided. Removes the logs of a run that passes.
+///
+pub fn check(config: &Config, outcome: &Outcome) {
+    let log_path = outcome.log_path.clone();
+    assert!(checker::check_last_run(config.clients, config.requests, config.participants, &log_path),
+            "check failed for logs in {}", log_path);
+    if config.bank {
+        assert!(checker::check_bank(config.participants, &log_path), "bank check failed for logs in {}", log_path);
+    }
+
+    let c = &outcome.coordinator;
+    assert_eq!(counter(c, "invariant_violations"), 0, "safety monitor violations, logs in {}", log_path);
+    for report in Some(c).into_iter().chain(outcome.participants.iter()) {
+        assert_eq!(counter(report, "illegal_transitions"), 0, "illegal transitions at {}, logs in {}", report.id, log_path);
+    }
+    let decided = counter(c, "global_commit") + counter(c, "global_abort");
+    assert_eq!(decided, (config.clients * config.requests) as u64, "undecided requests, logs in {}", log_path);
+    if config.operation_success_probability == 0.0 {
+        assert_eq!(counter(c, "global_commit"), 0, "committed without commit votes, logs in {}", log_path);
+    }
+    let _ = fs::remove_dir_all(&log_path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use harness::proptest::prelude::*;
+
+    ///
+    /// probability()
+    /// Shrinks towards a reliable system
+    ///
+    fn probability() -> impl Strategy<Value = f64> {
+        prop_oneof![Just(1.0), 0.5..1.0f64, Just(0.0)]
+    }
+
+    fn config() -> impl Strategy<Value = Config> {
+        (1..4u32, 1..3u32, 1..5u32, probability(), probability(),
+         proptest::option::of((0..4u32, 0..12u32)), any::<bool>(), any::<u64>())
+            .prop_map(|(participants, clients, requests, send, operation, crash, bank, seed)| Config {
+                participants,
+                clients,
+                requests,
+                send_success_probability: send,
+                operation_success_probability: operation,
+                crash: crash.map(|(p, after)| Crash { participant: p % participants, after }),
+                bank,
+                seed,
+            })
+    }
+
+    #[test]
+    fn reliable_run_commits_everything() {
+        let config = Config {
+            participants: 3,
+            clients: 2,
+            requests: 5,
+            send_success_probability: 1.0,
+            operation_success_probability: 1.0,
+            crash: None,
+            bank: false,
+            seed: 0,
+        };
+        let outcome = run(&config);
+        assert_eq!(counter(&outcome.coordinator, "global_commit"), 10);
+        for report in &outcome.participants {
+            assert_eq!(report.states.get("Quiescent"), Some(&10), "{} did not finish every transaction", report.id);
+        }
+        check(&config, &outcome);
+    }
+
+    #[test]
+    fn crash_after_commit_vote_keeps_bank_balanced() {
+        // The participant votes commit and crashes before the decision, so
+        // its half of the transfer is still in doubt when the check runs
+        let config = Config {
+            participants: 2,
+            clients: 1,
+            requests: 1,
+            send_success_probability: 1.0,
+            operation_success_probability: 1.0,
+            crash: Some(Crash { participant: 0, after: 1 }),
+            bank: true,
+            seed: 0,
+        };
+        let outcome = run(&config);
+        check(&config, &outcome);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(24))]
+
+        #[test]
+        fn runs_satisfy_checker_invariants(config in config()) {
+            let outcome = run(&config);
+            check(&config, &outcome);
+        }
+    }
+}
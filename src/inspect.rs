@@ -0,0 +1,186 @@
+//!
+//! inspect.rs
+//! Human readable dumps of OpLogs for debugging. Loads a log in any format
+//! without repairing it, prints the records that pass the txid, message type,
+//! sender and LSN filters as a table, and counts the records of each type.
+//!
+use std::collections::BTreeMap;
+
+use message::MessageType;
+use oplog::{LogRecord, OpLog};
+use tpcoptions::TPCOptions;
+
+///
+/// Filter
+/// Which records of a log to show; empty fields match everything
+///
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub txid: Option<String>,
+    pub mtype: Option<MessageType>,
+    pub sender: Option<String>,
+    pub lsn: Option<(u32, u32)>,   // Inclusive range
+}
+
+impl Filter {
+
+    ///
+    /// from_options(opts)
+    /// The filter given by the inspect options
+    ///
+    pub fn from_options(opts: &TPCOptions) -> Filter {
+        let non_empty = |s: &str| if s.is_empty() { None } else { Some(s.to_string()) };
+        Filter {
+            txid: non_empty(&opts.txid),
+            mtype: MessageType::parse(&opts.mtype),
+            sender: non_empty(&opts.sender),
+            lsn: parse_lsn_range(&opts.lsn),
+        }
+    }
+
+    ///
+    /// matches(rec)
+    /// Whether a record passes every filter
+    ///
+    pub fn matches(&self, rec: &LogRecord) -> bool {
+        self.txid.as_ref().is_none_or(|t| *t == rec.message.txid)
+            && self.mtype.is_none_or(|t| t == rec.message.mtype)
+            && self.sender.as_ref().is_none_or(|s| *s == rec.message.senderid)
+            && self.lsn.is_none_or(|(lo, hi)| lo <= rec.lsn && rec.lsn <= hi)
+    }
+
+    ///
+    /// select(log)
+    /// The matching records of a log in LSN order, starting from the most
+    /// selective index
+    ///
+    pub fn select(&self, log: &OpLog) -> Vec<LogRecord> {
+        let candidates = if let Some(ref txid) = self.txid {
+            log.by_txid(txid)
+        } else if let Some(ref sender) = self.sender {
+            log.by_sender(sender)
+        } else if let Some(mtype) = self.mtype {
+            log.by_type(mtype)
+        } else {
+            log.iter()
+        };
+        candidates.filter(|rec| self.matches(rec)).collect()
+    }
+}
+
+///
+/// parse_lsn_range(s)
+/// Parses "a..b" (inclusive), "a..", "..b" or "a"; None if malformed or empty
+///
+pub fn parse_lsn_range(s: &str) -> Option<(u32, u32)> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+    let bound = |b: &str, default: u32| if b.is_empty() { Some(default) } else { b.parse::<u32>().ok() };
+    let (lo, hi) = match s.find("..") {
+        Some(i) => (bound(&s[..i], 0)?, bound(&s[i + 2..], u32::MAX)?),
+        None => {
+            let n = s.parse::<u32>().ok()?;
+            (n, n)
+        }
+    };
+    if lo > hi {
+        return None;
+    }
+    Some((lo, hi))
+}
+
+///
+/// print_table(records)
+/// One row per record; times are relative to the first record shown
+///
+fn print_table(records: &[LogRecord]) {
+    let start = records.iter().map(|r| r.timestamp_us).filter(|t| *t > 0).min().unwrap_or(0);
+    let rows: Vec<[String; 7]> = records.iter().map(|r| {
+        let pm = &r.message;
+        [
+            r.lsn.to_string(),
+            if r.timestamp_us == 0 { "-".to_string() } else { format!("{:.3}", (r.timestamp_us - start) as f64 / 1000.0) },
+            format!("{:?}", pm.mtype),
+            pm.txid.clone(),
+            pm.senderid.clone(),
+            pm.opid.to_string(),
+            pm.transfer.map_or("-".to_string(), |t| format!("{}->{} {}", t.from, t.to, t.amount)),
+        ]
+    }).collect();
+
+    let header = ["LSN", "+ms", "TYPE", "TXID", "SENDER", "OPID", "TRANSFER"];
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells.iter().zip(widths.iter()).map(|(c, w)| format!("{:<w$}", c, w = *w)).collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(header.to_vec());
+    for row in &rows {
+        line(row.iter().map(|c| c.as_str()).collect());
+    }
+}
+
+///
+/// print_counts(records)
+/// Number of records of each message type
+///
+fn print_counts(records: &[LogRecord]) {
+    let mut counts = BTreeMap::new();
+    for r in records {
+        *counts.entry(format!("{:?}", r.message.mtype)).or_insert(0) += 1;
+    }
+    for (mtype, n) in &counts {
+        println!("  {:<24}{}", mtype, n);
+    }
+    println!("  {:<24}{}", "total", records.len());
+}
+
+///
+/// inspect_logs(opts)
+///
+/// Prints opts.log_file, or the coordinator and every participant log in
+/// opts.log_path, filtered by the inspect options. Returns false if a log
+/// could not be loaded.
+///
+pub fn inspect_logs(opts: &TPCOptions) -> bool {
+    let paths = if !opts.log_file.is_empty() {
+        vec![opts.log_file.clone()]
+    } else {
+        let mut paths = vec![format!("{}//{}", opts.log_path, "coordinator.log")];
+        paths.extend((0..opts.num_participants).map(|pid| format!("{}//participant_{}.log", opts.log_path, pid)));
+        paths
+    };
+    let filter = Filter::from_options(opts);
+
+    let mut result = true;
+    for path in paths {
+        let log = match OpLog::read_only(path.clone()) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Failed to load log \"{}\": {}", path, e);
+                result = false;
+                continue;
+            }
+        };
+        let records = filter.select(&log);
+        println!("== {} ({:?}, {} records, {} shown)", path, log.format(), log.iter().len(), records.len());
+        if let Some(tail) = log.torn_tail() {
+            println!("   torn tail of {} bytes at offset {} not shown ({})", tail.discarded_bytes, tail.offset, tail.reason);
+        }
+        if log.checkpointed() {
+            println!("   log was checkpointed: transactions decided before the checkpoint are not shown");
+        }
+        print_table(&records);
+        println!("Counts:");
+        print_counts(&records);
+        println!();
+    }
+    result
+}
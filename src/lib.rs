@@ -0,0 +1,36 @@
+//!
+//! lib.rs
+//! The protocol roles, logs and tools of the _T_wo _P_hase _C_ommit project.
+//! main.rs builds the command line program on top of them; keeping them in
+//! a library also lets the fuzz targets in fuzz/ reach the decoders.
+//!
+#[macro_use]
+extern crate log;
+extern crate clap;
+extern crate ipc_channel;
+extern crate tracing;
+
+pub mod message;
+pub mod oplog;
+pub mod protocol;
+pub mod coordinator;
+pub mod explore;
+pub mod participant;
+pub mod client;
+pub mod checker;
+pub mod clock;
+pub mod dashboard;
+pub mod graph;
+#[cfg(test)]
+pub mod harness;
+pub mod inspect;
+pub mod metrics;
+pub mod monitor;
+pub mod tpcoptions;
+pub mod bank;
+pub mod stats;
+pub mod summary;
+pub mod sweep;
+pub mod timeline;
+pub mod trace;
+pub mod workload;
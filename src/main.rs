@@ -1,35 +1,32 @@
 #[macro_use]
 extern crate log;
-extern crate stderrlog;
 extern crate clap;
 extern crate ctrlc;
 extern crate ipc_channel;
+extern crate tracing;
+extern crate tracing_subscriber;
+extern crate two_phase_commit;
 use std::env;
 use std::fs;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::process::{Child,Command,Stdio};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use std::thread;
-use std::error::Error;
+use std::io;
 
 use ipc_channel::ipc::IpcSender as Sender;
 use ipc_channel::ipc::IpcReceiver as Receiver;
 use ipc_channel::ipc::IpcOneShotServer;
 use ipc_channel::ipc::channel;
-pub mod message;
-pub mod oplog;
-pub mod coordinator;
-pub mod participant;
-pub mod client;
-pub mod checker;
-pub mod tpcoptions;
+use two_phase_commit::{bank, checker, client, coordinator, dashboard, explore, graph, inspect, message, metrics,
+                       monitor, oplog, participant, stats, summary, sweep, timeline, tpcoptions, trace, workload};
 use message::ProtocolMessage;
-use message::MessageType;
-use message::RequestStatus;
 use client::Client;
 use participant::Participant;
-use std::io::Write;
+use summary::{ChildSummary, RunSummary};
+use workload::Arrival;
+use bank::{Bank, BankConfig};
 ///
 /// pub fn spawn_child_and_connect(child_opts: &mut tpcoptions::TPCOptions) -> (std::process::Child, Sender<ProtocolMessage>, Receiver<ProtocolMessage>)
 ///
@@ -49,11 +46,15 @@ fn spawn_child_and_connect(child_opts: &mut tpcoptions::TPCOptions, mode: &str,
     let (server, server_name) = IpcOneShotServer::new().expect("Failed to create IPC one-shot server");
     opts.ipc_path = server_name.clone();
 
-    let child = Command::new(env::current_exe().unwrap())
-        .stdin(Stdio::piped())
-        .args(opts.as_vec())
-        .spawn()
-        .expect("Failed to execute child process");
+    let mut command = Command::new(env::current_exe().unwrap());
+    command.stdin(Stdio::piped()).args(opts.as_vec());
+    if opts.tui {
+        // Keep the children from drawing over the dashboard
+        let path = output_path(&opts.log_path, &format!("{}_{}", mode, num));
+        let out = fs::File::create(&path).expect("Failed to create child output file");
+        command.stderr(out.try_clone().expect("Failed to clone child output file")).stdout(out);
+    }
+    let child = command.spawn().expect("Failed to execute child process");
 
     // let (tx, rx) = channel().unwrap().expect("Failed to create an IPC channel");
     // TODO
@@ -124,47 +125,119 @@ fn connect_to_coordinator(opts: &tpcoptions::TPCOptions) -> (Sender<ProtocolMess
 ///         set to be false whenever Ctrl+C is pressed
 ///
 /// 1. Creates a new coordinator
-/// 2. Spawns and connects to new clients processes and then registers them with
-///    the coordinator
-/// 3. Spawns and connects to new participant processes and then registers them
+/// 2. Spawns and connects to new participant processes and then registers them
 ///    with the coordinator
+/// 3. Spawns and connects to new clients processes and then registers them with
+///    the coordinator
 /// 4. Starts the coordinator protocol
 /// 5. Wait until the children finish execution
+/// 6. Writes summary.json with every role's report to log_path, and
+///    trace.json with every role's trace events
 ///
 fn run(opts: &mut tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
+    let started = Instant::now();
+    let started_at = SystemTime::now();
     let coord_log_path = format!("{}//{}", opts.log_path, "coordinator.log");
     // TODO
     let (tx_coor_client, rx_coor_client): (Sender<ProtocolMessage>, Receiver<ProtocolMessage>) = channel().unwrap();
     let (tx_coor_part, rx_coor_part): (Sender<ProtocolMessage>, Receiver<ProtocolMessage>) = channel().unwrap();
     let mut clients = Vec::new();
     let mut participants = Vec::new();
-    let mut coordinator = coordinator::Coordinator::new(coord_log_path, &running, opts.num_requests,rx_coor_client,rx_coor_part);
+    let arrival = Arrival::new(&opts.arrival, opts.rate, opts.think_time);
+    let mut coordinator = coordinator::Coordinator::new(coord_log_path, oplog::LogConfig::from_options(opts), &running, opts.num_requests,rx_coor_client,rx_coor_part, arrival.idle_timeout(), monitor::OnViolation::parse(&opts.on_violation).unwrap());
     
+    // Participants go first so that clients, which start issuing requests as
+    // soon as they are connected, never wait on a half-built system.
+    for i in 0..opts.num_participants {
+        let participant_id_str = format!("participant_{}", i); // Unique identifier for each participant
+        // Drop the reports of a previous run so a crashed child is not credited with them
+        let _ = fs::remove_file(stats::report_path(&opts.log_path, &participant_id_str));
+        let _ = fs::remove_file(trace::trace_path(&opts.log_path, &participant_id_str));
+        let _ = fs::remove_file(bank::balances_path(&opts.log_path, &participant_id_str));
+        let (child, tx) = spawn_child_and_connect(opts, "participant", i,tx_coor_part.clone());
+        coordinator.participant_join(&participant_id_str, tx);
+        participants.push((participant_id_str, child));
+    }
+
     for i in 0..opts.num_clients {
         let client_id_str = format!("client_{}", i); 
+        let _ = fs::remove_file(stats::report_path(&opts.log_path, &client_id_str));
+        let _ = fs::remove_file(trace::trace_path(&opts.log_path, &client_id_str));
         let (child, tx) = spawn_child_and_connect(opts, "client", i, tx_coor_client.clone());
         coordinator.client_join(&client_id_str, tx);
-        clients.push(child);
+        clients.push((client_id_str, child));
     }
-    
-    for i in 0..opts.num_participants {
-        let participant_id_str = format!("participant_{}", i); // Unique identifier for each participant
-        let participant_log_path = format!("{}//{}.log", opts.log_path, participant_id_str); // Log path for each participant
-        let (child, tx) = spawn_child_and_connect(opts, "participant", i,tx_coor_part.clone());
-        coordinator.participant_join(&participant_id_str, tx);
-        participants.push(child);
+    if !opts.metrics_addr.is_empty() {
+        let addr = opts.metrics_addr.parse().expect("Invalid metrics address");
+        if let Err(e) = coordinator.serve_metrics(addr) {
+            error!("Failed to serve metrics on {}: {:?}", addr, e);
+        }
     }
     // Start the coordinator protocol
+    let dashboard = if opts.tui {
+        Some(dashboard::Dashboard::start(coordinator.progress(), opts.num_clients * opts.num_requests, running.clone()))
+    } else {
+        None
+    };
     coordinator.protocol();
+    if let Some(dashboard) = dashboard {
+        dashboard.stop();
+    }
 
     // Wait for child processes to complete
-    for mut client in clients {
-        client.wait().expect("Failed to wait on client");
+    let mut trace_paths = vec![trace::trace_path(&opts.log_path, "coordinator")];
+    trace_paths.extend(participants.iter().chain(clients.iter()).map(|(id, _)| trace::trace_path(&opts.log_path, id)));
+    let mut client_summaries = Vec::new();
+    for (id, mut client) in clients {
+        let status = client.wait().expect("Failed to wait on client");
+        client_summaries.push(ChildSummary::new(&id, &status, &opts.log_path));
     }
-    for mut participant in participants {
-        participant.wait().expect("Failed to wait on participant");
+    let mut participant_summaries = Vec::new();
+    for (id, mut participant) in participants {
+        let status = participant.wait().expect("Failed to wait on participant");
+        participant_summaries.push(ChildSummary::new(&id, &status, &opts.log_path));
     }
     coordinator.report_status();
+    let coordinator_report = coordinator.stats_report();
+    write_stats_report(&coordinator_report, &opts.log_path);
+    write_trace(&coordinator.tracer().to_file(), &trace::trace_path(&opts.log_path, "coordinator"));
+    write_trace(&trace::merge(&trace_paths), &trace::merged_trace_path(&opts.log_path));
+
+    let summary = RunSummary::new(opts, started_at, started.elapsed(), &coordinator_report,
+                                  participant_summaries, client_summaries);
+    let summary_path = summary::summary_path(&opts.log_path);
+    if let Err(e) = summary.write_json(&summary_path) {
+        error!("Failed to write run summary \"{}\": {:?}", summary_path, e);
+    }
+}
+
+///
+/// write_stats_report(report, log_path)
+/// Saves the counters and latency histograms of one role as JSON in log_path
+///
+fn write_stats_report(report: &stats::StatsReport, log_path: &str) {
+    let path = stats::report_path(log_path, &report.id);
+    if let Err(e) = report.write_json(&path) {
+        error!("Failed to write stats report \"{}\": {:?}", path, e);
+    }
+}
+
+///
+/// write_trace(trace, path)
+/// Saves trace events in the Chrome trace-event format
+///
+///
+/// output_path()
+/// Where a process's stdout and stderr go when the dashboard owns the terminal
+///
+fn output_path(log_path: &str, id: &str) -> String {
+    format!("{}//{}.out", log_path, id)
+}
+
+fn write_trace(trace: &trace::TraceFile, path: &str) {
+    if let Err(e) = trace.write_json(path) {
+        error!("Failed to write trace \"{}\": {:?}", path, e);
+    }
 }
 
 ///
@@ -191,11 +264,15 @@ fn run_client(opts: & tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
         Arc::clone(&running),
         tx,
         rx,
-        opts.num_requests
+        opts.num_requests,
+        Arrival::new(&opts.arrival, opts.rate, opts.think_time),
+        BankConfig::from_options(opts)
     );
 
     // Starts the client protocol
     client.protocol(num_requests);
+    write_stats_report(&client.stats_report(), &opts.log_path);
+    write_trace(&client.tracer().to_file(), &trace::trace_path(&opts.log_path, &client.id_str));
 }
 
 ///
@@ -217,27 +294,75 @@ fn run_participant(opts: & tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
     let mut participant = Participant::new(
         format!("participant_{}",opts.num), 
         participant_log_path,
+        oplog::LogConfig::from_options(opts),
         running.clone(), 
         opts.send_success_probability,
         opts.operation_success_probability,
         tx, 
-        rx);
+        rx,
+        BankConfig::from_options(opts).map(|config| Bank::new(config, opts.num)));
+    if !opts.metrics_addr.is_empty() {
+        let addr = metrics::participant_addr(opts.metrics_addr.parse().expect("Invalid metrics address"), opts.num);
+        if let Err(e) = participant.serve_metrics(addr) {
+            error!("Failed to serve metrics on {}: {:?}", addr, e);
+        }
+    }
     // Starts the participant protocol
     participant.protocol();
+    if let Some(snapshot) = participant.bank_snapshot() {
+        let path = bank::balances_path(&opts.log_path, &format!("participant_{}", opts.num));
+        if let Err(e) = snapshot.write_json(&path) {
+            error!("Failed to write balances \"{}\": {:?}", path, e);
+        }
+    }
+    write_stats_report(&participant.stats_report(), &opts.log_path);
+    write_trace(&participant.tracer().to_file(), &trace::trace_path(&opts.log_path, &format!("participant_{}", opts.num)));
 
 }
 
+///
+/// init_logging(opts)
+/// Send log!() records and tracing events of this crate to stderr, either as
+/// text or as one JSON object per line (--log_output json). The JSON lines
+/// carry the fields of the enclosing transaction span, so grepping every
+/// process's stderr for a txid yields that transaction's full path.
+///
+fn init_logging(opts: &tpcoptions::TPCOptions) {
+    use tracing_subscriber::filter::{LevelFilter, Targets};
+    use tracing_subscriber::fmt;
+    use tracing_subscriber::fmt::writer::BoxMakeWriter;
+    use tracing_subscriber::prelude::*;
+
+    // Same levels as stderrlog's verbosity
+    let level = match opts.verbosity {
+        0 => LevelFilter::ERROR,
+        1 => LevelFilter::WARN,
+        2 => LevelFilter::INFO,
+        3 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let filter = Targets::new().with_target(module_path!(), level);
+    let registry = tracing_subscriber::registry().with(filter);
+    // The dashboard owns the terminal, so the coordinator logs to a file
+    let mut writer = BoxMakeWriter::new(io::stderr);
+    if opts.tui && opts.mode == "run" {
+        let _ = fs::create_dir_all(&opts.log_path);
+        if let Ok(file) = fs::File::create(output_path(&opts.log_path, "coordinator")) {
+            writer = BoxMakeWriter::new(Mutex::new(file));
+        }
+    }
+    if opts.log_output == "json" {
+        registry.with(fmt::layer().json().with_writer(writer)).init();
+    } else {
+        registry.with(fmt::layer().with_writer(writer)).init();
+    }
+}
+
 fn main() {
     // Parse CLI arguments
     let mut opts = tpcoptions::TPCOptions::new();
     // Set-up logging and create OpLog path if necessary
-    stderrlog::new()
-            .module(module_path!())
-            .quiet(false)
-            .timestamp(stderrlog::Timestamp::Millisecond)
-            .verbosity(opts.verbosity)
-            .init()
-            .unwrap();
+    init_logging(&opts);
     match fs::create_dir_all(opts.log_path.clone()) {
         Err(e) => error!("Failed to create log_path: \"{:?}\". Error \"{:?}\"", opts.log_path, e),
         _ => (),
@@ -260,7 +385,36 @@ fn main() {
         "run" => run(&mut opts, running),
         "client" => run_client(&opts, running),
         "participant" => run_participant(&opts, running),
-        "check" => checker::check_last_run(opts.num_clients, opts.num_requests, opts.num_participants, &opts.log_path),
+        "check" => {
+            if !checker::check_last_run(opts.num_clients, opts.num_requests, opts.num_participants, &opts.log_path) {
+                std::process::exit(1);
+            }
+            if opts.workload == "bank" && !checker::check_bank(opts.num_participants, &opts.log_path) {
+                std::process::exit(1);
+            }
+        },
+        "sweep" => sweep::run_sweep(&opts, running),
+        "inspect" => {
+            if !inspect::inspect_logs(&opts) {
+                std::process::exit(1);
+            }
+        },
+        "timeline" => {
+            if !timeline::write_timeline(&opts) {
+                std::process::exit(1);
+            }
+        },
+        "graph" => graph::print_graph(&opts),
+        "explore" => {
+            if !explore::run_explore(&opts) {
+                std::process::exit(1);
+            }
+        },
+        "verify-log" => {
+            if !checker::verify_logs(&opts) {
+                std::process::exit(1);
+            }
+        },
         _ => panic!("Unknown mode"),
     }
 }
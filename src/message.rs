@@ -15,7 +15,7 @@ use self::serde_json::Value;
 ///
 /// HINT: You should not need to modify this, but can add to it if necessary
 ///
-#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum MessageType {
     ClientRequest,          // Request a transaction from the coordinator
     CoordinatorPropose,     // Coordinator sends propose work to participants
@@ -28,6 +28,17 @@ pub enum MessageType {
     CoordinatorExit,        // Coordinator telling client/participant about shut down
 }
 
+impl MessageType {
+
+    ///
+    /// parse(name)
+    /// The message type with the given variant name, e.g. "CoordinatorCommit"
+    ///
+    pub fn parse(name: &str) -> Option<MessageType> {
+        serde_json::from_value(Value::String(name.to_string())).ok()
+    }
+}
+
 ///
 /// RequestStatus
 /// Status of request from client.
@@ -41,6 +52,18 @@ pub enum RequestStatus {
     Unknown,                // Request status unknown (typically timed out)
 }
 
+///
+/// Transfer
+/// Payload of a bank-transfer request: move `amount` from account `from` to
+/// account `to`. Accounts are sharded across participants.
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transfer {
+    pub from: u32,
+    pub to: u32,
+    pub amount: i64,
+}
+
 /// generator for unique ids of messages
 static COUNTER: AtomicU32 = AtomicU32::new(1);
 
@@ -61,6 +84,10 @@ pub struct ProtocolMessage {
     pub txid: String,        // Transaction ID from the client (unique relative to other transactions)
     pub senderid: String,    // Sender ID (unique across all senders)
     pub opid: u32,           // Operation ID (relative to the original client who started this transaction)
+    #[serde(default)]
+    pub transfer: Option<Transfer>, // Operation payload for the bank workload
+    #[serde(default)]
+    pub lamport: u64,               // Sender's Lamport time when sent (0 if never stamped)
 }
 
 ///
@@ -74,6 +101,8 @@ impl ProtocolMessage {
             txid: tid,
             senderid: sid,
             opid: oid,
+            transfer: None,
+            lamport: 0,
         }
     }
     pub fn instantiate(t: MessageType, u: u32, tid: String, sid: String, oid: u32) -> ProtocolMessage {
@@ -83,12 +112,13 @@ impl ProtocolMessage {
             txid: tid,
             senderid: sid,
             opid: oid,
+            transfer: None,
+            lamport: 0,
         }
     }
-    pub fn from_string(line: &String) -> ProtocolMessage {
-        let data: Value = serde_json::from_str(&line.to_string()).unwrap();
-        let pm: ProtocolMessage = serde_json::from_value(data).unwrap();
-        pm
+    pub fn from_string(line: &str) -> Result<ProtocolMessage, serde_json::Error> {
+        let data: Value = serde_json::from_str(line)?;
+        serde_json::from_value(data)
     }
 
 }
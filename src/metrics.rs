@@ -0,0 +1,148 @@
+//!
+//! metrics.rs
+//! Loopback HTTP endpoint serving a role's counters and latency histograms
+//! in the Prometheus text exposition format. The role publishes a copy of
+//! its StatsReport while it runs; the server thread renders the latest copy
+//! on every scrape of /metrics.
+//!
+extern crate tiny_http;
+
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use stats::StatsReport;
+
+/// Minimum time between two publications of a role's report
+const PUBLISH_INTERVAL_MS: u64 = 250;
+
+///
+/// MetricsPublisher
+/// The role's side of the endpoint: holds the report the server renders
+///
+#[derive(Debug)]
+pub struct MetricsPublisher {
+    report: Arc<Mutex<StatsReport>>,
+    last_publish: Option<Instant>,
+}
+
+impl MetricsPublisher {
+
+    ///
+    /// start(addr, id)
+    /// Binds addr and serves /metrics from a background thread for the rest
+    /// of the process's life. Until the first publish() the endpoint only
+    /// carries the role's id.
+    ///
+    pub fn start(addr: SocketAddr, id: &str) -> io::Result<MetricsPublisher> {
+        let server = tiny_http::Server::http(addr)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        let report = Arc::new(Mutex::new(StatsReport { id: id.to_string(), ..StatsReport::default() }));
+        let shared = report.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = if request.url() == "/metrics" {
+                    let body = match shared.lock() {
+                        Ok(report) => render(&report),
+                        Err(_) => String::new(),
+                    };
+                    let content_type = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+                        .expect("Invalid header");
+                    tiny_http::Response::from_string(body).with_header(content_type)
+                } else {
+                    tiny_http::Response::from_string("Not found\n").with_status_code(404)
+                };
+                if let Err(e) = request.respond(response) {
+                    warn!("Failed to answer metrics request: {:?}", e);
+                }
+            }
+        });
+        Ok(MetricsPublisher { report, last_publish: None })
+    }
+
+    ///
+    /// due()
+    /// Whether enough time has passed since the last publish() to make
+    /// building a new report worthwhile
+    ///
+    pub fn due(&self) -> bool {
+        self.last_publish.is_none_or(|t| t.elapsed() >= Duration::from_millis(PUBLISH_INTERVAL_MS))
+    }
+
+    ///
+    /// publish(report)
+    /// Replaces the report served on /metrics
+    ///
+    pub fn publish(&mut self, report: StatsReport) {
+        if let Ok(mut shared) = self.report.lock() {
+            *shared = report;
+        }
+        self.last_publish = Some(Instant::now());
+    }
+}
+
+///
+/// participant_addr(addr, num)
+/// Participants serve their metrics on the ports following the
+/// coordinator's: participant_<num> uses addr's port + 1 + num
+///
+pub fn participant_addr(addr: SocketAddr, num: u32) -> SocketAddr {
+    let mut addr = addr;
+    addr.set_port(addr.port() + 1 + num as u16);
+    addr
+}
+
+///
+/// render(report)
+/// Prometheus text format of a report: every counter as tpc_<name>_total,
+/// completed transactions, throughput, the current state (tpc_state, 1 for
+/// the current one) and the number of transactions in every state
+/// (tpc_transactions) of the 2PC roles, and one tpc_latency_seconds
+/// histogram per measured phase, all labelled with the role's id
+///
+pub fn render(report: &StatsReport) -> String {
+    let mut out = String::new();
+    let id = format!("id=\"{}\"", report.id);
+    for (name, value) in &report.counters {
+        let _ = writeln!(out, "# TYPE tpc_{}_total counter", name);
+        let _ = writeln!(out, "tpc_{}_total{{{}}} {}", name, id, value);
+    }
+    let _ = writeln!(out, "# TYPE tpc_completed_total counter");
+    let _ = writeln!(out, "tpc_completed_total{{{}}} {}", id, report.completed);
+    let _ = writeln!(out, "# TYPE tpc_throughput gauge");
+    let _ = writeln!(out, "tpc_throughput{{{}}} {}", id, report.throughput);
+    if !report.states.is_empty() {
+        let _ = writeln!(out, "# TYPE tpc_state gauge");
+        for name in report.states.keys() {
+            let current = if *name == report.state { 1 } else { 0 };
+            let _ = writeln!(out, "tpc_state{{{},state=\"{}\"}} {}", id, name, current);
+        }
+        let _ = writeln!(out, "# TYPE tpc_transactions gauge");
+        for (name, count) in &report.states {
+            let _ = writeln!(out, "tpc_transactions{{{},state=\"{}\"}} {}", id, name, count);
+        }
+    }
+
+    if report.latencies.is_empty() {
+        return out;
+    }
+    let _ = writeln!(out, "# TYPE tpc_latency_seconds histogram");
+    for (phase, l) in &report.latencies {
+        let labels = format!("{},phase=\"{}\"", id, phase);
+        let mut cumulative = 0;
+        for b in &l.buckets {
+            cumulative += b.count;
+            let le = match b.le_us {
+                Some(us) => (us as f64 / 1e6).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "tpc_latency_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, cumulative);
+        }
+        let _ = writeln!(out, "tpc_latency_seconds_sum{{{}}} {}", labels, l.sum_us as f64 / 1e6);
+        let _ = writeln!(out, "tpc_latency_seconds_count{{{}}} {}", labels, l.count);
+    }
+    out
+}
@@ -0,0 +1,282 @@
+//!
+//! monitor.rs
+//! Online safety monitor for the coordinator. While the protocol runs the
+//! coordinator reports every proposal, vote and decision to the monitor,
+//! which checks the 2PC safety invariants as they happen instead of after
+//! the fact from the logs (see checker.rs).
+//!
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use message::MessageType;
+
+/// Number of decisions the monitor remembers. Older transactions are
+/// forgotten: a straggling vote for one then reads as a vote on a
+/// transaction that was never proposed, and a second decision goes unseen.
+const DECIDED_WINDOW: usize = 4096;
+
+///
+/// OnViolation
+/// What the coordinator does once the monitor reports a violation
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnViolation {
+    Log,    // Log it and carry on
+    Halt,   // Log it, drop the offending vote or decision and stop the run
+}
+
+impl OnViolation {
+
+    ///
+    /// parse(spec)
+    /// "log" or "halt"
+    ///
+    pub fn parse(spec: &str) -> Option<OnViolation> {
+        match spec {
+            "log" => Some(OnViolation::Log),
+            "halt" => Some(OnViolation::Halt),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Violation
+/// One broken invariant, with everything the monitor knew about the
+/// transaction at the time
+///
+#[derive(Clone, Debug)]
+pub struct Violation {
+    pub invariant: &'static str,
+    pub txid: String,
+    pub detail: String,
+    pub votes: BTreeMap<String, MessageType>,   // Votes received for txid, by participant
+    pub participants: BTreeSet<String>,         // Registered participants
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} violated for {}: {} (votes: {:?}, participants: {:?})",
+               self.invariant, self.txid, self.detail, self.votes, self.participants)
+    }
+}
+
+///
+/// SafetyMonitor
+/// Per-transaction bookkeeping of what the coordinator proposed, received
+/// and decided
+///
+#[derive(Debug, Default)]
+pub struct SafetyMonitor {
+    participants: BTreeSet<String>,
+    proposed: HashSet<String>,
+    votes: HashMap<String, BTreeMap<String, MessageType>>,
+    decided: HashMap<String, MessageType>,
+    decided_order: VecDeque<String>,            // Keys of decided, oldest first
+    violations: u32,
+}
+
+impl SafetyMonitor {
+
+    ///
+    /// new()
+    /// A monitor that knows no participants and no transactions yet
+    ///
+    pub fn new() -> SafetyMonitor {
+        SafetyMonitor::default()
+    }
+
+    ///
+    /// violations()
+    /// Number of violations reported so far
+    ///
+    pub fn violations(&self) -> u32 {
+        self.violations
+    }
+
+    ///
+    /// participant_joined(name)
+    /// Registers a participant whose vote every commit decision needs
+    ///
+    pub fn participant_joined(&mut self, name: &str) {
+        self.participants.insert(name.to_string());
+    }
+
+    ///
+    /// proposed(txid)
+    /// The coordinator sent the proposal of txid
+    ///
+    pub fn proposed(&mut self, txid: &str) {
+        self.proposed.insert(txid.to_string());
+    }
+
+    ///
+    /// vote(txid, participant, vote)
+    /// The coordinator received a vote. Only proposed, undecided
+    /// transactions get votes, only registered participants vote, and
+    /// nobody changes their mind.
+    ///
+    pub fn vote(&mut self, txid: &str, participant: &str, vote: MessageType) -> Result<(), Violation> {
+        if self.decided.contains_key(txid) {
+            // A straggler: the coordinator gave up waiting and already decided
+            return Ok(());
+        }
+        if !self.proposed.contains(txid) {
+            return Err(self.violation("vote-after-proposal", txid,
+                                      format!("{} voted {:?} on a transaction that was never proposed", participant, vote)));
+        }
+        if !self.participants.contains(participant) {
+            return Err(self.violation("registered-voter", txid,
+                                      format!("vote {:?} from unregistered participant {}", vote, participant)));
+        }
+        // Keep the first vote, so the report shows what the participant
+        // originally voted and a later decision is checked against it
+        let votes = self.votes.entry(txid.to_string()).or_default();
+        match votes.get(participant).cloned() {
+            Some(previous) if previous != vote => {
+                Err(self.violation("single-vote", txid,
+                                   format!("{} voted {:?} after voting {:?}", participant, vote, previous)))
+            },
+            Some(_) => Ok(()),
+            None => {
+                votes.insert(participant.to_string(), vote);
+                Ok(())
+            },
+        }
+    }
+
+    ///
+    /// decided(txid, decision)
+    /// The coordinator is about to log and send its decision. A transaction
+    /// is decided once, after its proposal, and commits only with a commit
+    /// vote from every registered participant.
+    ///
+    pub fn decided(&mut self, txid: &str, decision: MessageType) -> Result<(), Violation> {
+        if let Some(previous) = self.decided.get(txid) {
+            let detail = format!("decided {:?} after already deciding {:?}", decision, previous);
+            return Err(self.violation("single-decision", txid, detail));
+        }
+        if !self.proposed.contains(txid) {
+            return Err(self.violation("decision-after-proposal", txid,
+                                      format!("decided {:?} without proposing", decision)));
+        }
+        if decision == MessageType::CoordinatorCommit {
+            let votes = self.votes.get(txid);
+            let missing: Vec<&String> = self.participants.iter()
+                .filter(|p| votes.and_then(|v| v.get(*p)) != Some(&MessageType::ParticipantVoteCommit))
+                .collect();
+            if !missing.is_empty() {
+                let detail = format!("committed without a commit vote from {:?}", missing);
+                return Err(self.violation("unanimous-commit", txid, detail));
+            }
+        }
+        self.decided.insert(txid.to_string(), decision);
+        self.decided_order.push_back(txid.to_string());
+        if self.decided_order.len() > DECIDED_WINDOW {
+            if let Some(oldest) = self.decided_order.pop_front() {
+                self.decided.remove(&oldest);
+            }
+        }
+        self.proposed.remove(txid);
+        self.votes.remove(txid);
+        Ok(())
+    }
+
+    ///
+    /// violation(invariant, txid, detail)
+    /// Counts a violation and captures the transaction's context
+    ///
+    fn violation(&mut self, invariant: &'static str, txid: &str, detail: String) -> Violation {
+        self.violations += 1;
+        Violation {
+            invariant,
+            txid: txid.to_string(),
+            detail,
+            votes: self.votes.get(txid).cloned().unwrap_or_default(),
+            participants: self.participants.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use message::MessageType;
+    use super::*;
+
+    /// A monitor with two registered participants
+    fn monitor() -> SafetyMonitor {
+        let mut m = SafetyMonitor::new();
+        m.participant_joined("participant_0");
+        m.participant_joined("participant_1");
+        m
+    }
+
+    /// Proposes txid and collects a commit vote from both participants
+    fn prepare(m: &mut SafetyMonitor, txid: &str) {
+        m.proposed(txid);
+        m.vote(txid, "participant_0", MessageType::ParticipantVoteCommit).unwrap();
+        m.vote(txid, "participant_1", MessageType::ParticipantVoteCommit).unwrap();
+    }
+
+    #[test]
+    fn unanimous_commit_is_accepted() {
+        let mut m = monitor();
+        prepare(&mut m, "tx_0");
+        assert!(m.decided("tx_0", MessageType::CoordinatorCommit).is_ok());
+        assert_eq!(m.violations(), 0);
+    }
+
+    #[test]
+    fn each_invariant_is_reported() {
+        let mut m = monitor();
+        assert_eq!(m.vote("tx_0", "participant_0", MessageType::ParticipantVoteCommit).unwrap_err().invariant, "vote-after-proposal");
+        assert_eq!(m.decided("tx_0", MessageType::CoordinatorAbort).unwrap_err().invariant, "decision-after-proposal");
+
+        m.proposed("tx_1");
+        assert_eq!(m.vote("tx_1", "participant_7", MessageType::ParticipantVoteCommit).unwrap_err().invariant, "registered-voter");
+        m.vote("tx_1", "participant_0", MessageType::ParticipantVoteCommit).unwrap();
+        let changed = m.vote("tx_1", "participant_0", MessageType::ParticipantVoteAbort).unwrap_err();
+        assert_eq!(changed.invariant, "single-vote");
+        assert_eq!(changed.votes.get("participant_0"), Some(&MessageType::ParticipantVoteCommit));
+
+        m.proposed("tx_2");
+        m.vote("tx_2", "participant_0", MessageType::ParticipantVoteCommit).unwrap();
+        let partial = m.decided("tx_2", MessageType::CoordinatorCommit).unwrap_err();
+        assert_eq!(partial.invariant, "unanimous-commit");
+        assert!(partial.detail.contains("participant_1"));
+
+        m.decided("tx_2", MessageType::CoordinatorAbort).unwrap();
+        assert_eq!(m.decided("tx_2", MessageType::CoordinatorAbort).unwrap_err().invariant, "single-decision");
+        assert_eq!(m.violations(), 6);
+    }
+
+    #[test]
+    fn abort_needs_no_votes_and_stragglers_are_ignored() {
+        let mut m = monitor();
+        m.proposed("tx_0");
+        m.decided("tx_0", MessageType::CoordinatorAbort).unwrap();
+        assert!(m.vote("tx_0", "participant_1", MessageType::ParticipantVoteCommit).is_ok());
+        assert_eq!(m.violations(), 0);
+        assert_eq!(OnViolation::parse("halt"), Some(OnViolation::Halt));
+        assert_eq!(OnViolation::parse("panic"), None);
+    }
+
+    #[test]
+    fn decisions_are_forgotten_beyond_the_window() {
+        let mut m = monitor();
+        for i in 0..DECIDED_WINDOW + 10 {
+            let txid = format!("tx_{}", i);
+            prepare(&mut m, &txid);
+            m.decided(&txid, MessageType::CoordinatorCommit).unwrap();
+        }
+        assert_eq!(m.decided.len(), DECIDED_WINDOW);
+        assert_eq!(m.decided_order.len(), DECIDED_WINDOW);
+        assert!(m.proposed.is_empty() && m.votes.is_empty());
+
+        // Still in the window: a straggler is fine, a second decision is not
+        let recent = format!("tx_{}", DECIDED_WINDOW + 9);
+        assert!(m.vote(&recent, "participant_0", MessageType::ParticipantVoteAbort).is_ok());
+        assert_eq!(m.decided(&recent, MessageType::CoordinatorAbort).unwrap_err().invariant, "single-decision");
+        assert!(!m.decided.contains_key("tx_0"));
+    }
+}
@@ -1,101 +1,721 @@
 extern crate serde;
 extern crate serde_json;
 extern crate bincode;
+extern crate crc32fast;
+extern crate sha2;
 
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::BufReader;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::BufWriter;
 use std::io::prelude::*;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec;
+
+use oplog::sha2::{Digest, Sha256};
 
 use message;
+use tpcoptions::TPCOptions;
+
+/// First bytes of a binary log file: magic string plus format version
+const BINARY_MAGIC: &[u8; 8] = b"2PCLOG\x04\x00";
+
+/// Schema version of the records written by this build. Version 1 records
+/// are the bare ProtocolMessages of JSON logs written before records were
+/// versioned; they still load, numbered in file order, and their messages
+/// have no Lamport time (it reads as 0).
+pub const RECORD_VERSION: u32 = 4;
+
+/// prev_hash of the first record of a log
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Size of the length + CRC32 prefix of every binary record
+const BINARY_RECORD_HEADER: usize = 8;
+
+///
+/// LogFormat
+/// On-disk encoding of an Operations Log
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Json,       // One JSON object per line
+    Binary,     // Magic header, then length-prefixed, CRC32-checked bincode records
+}
+
+impl LogFormat {
+
+    ///
+    /// parse(s)
+    /// Parses a format name as given on the command line
+    ///
+    pub fn parse(s: &str) -> Option<LogFormat> {
+        match s {
+            "json" => Some(LogFormat::Json),
+            "binary" => Some(LogFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Durability
+/// When appended records are pushed to the OS and to stable storage
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    None,                                           // Buffer records, no flush or sync
+    Flush,                                          // Flush every record to the OS
+    Fsync,                                          // Flush and sync_data every record
+    GroupCommit { records: u32, interval_ms: u64 }, // Sync every N records or once M ms have passed
+}
+
+impl Durability {
+
+    ///
+    /// parse(s)
+    /// Parses "none", "flush", "fsync" or "group:<records>:<ms>"
+    ///
+    pub fn parse(s: &str) -> Option<Durability> {
+        match s {
+            "none" => Some(Durability::None),
+            "flush" => Some(Durability::Flush),
+            "fsync" => Some(Durability::Fsync),
+            _ => {
+                let parts: Vec<&str> = s.split(':').collect();
+                if parts.len() != 3 || parts[0] != "group" {
+                    return None;
+                }
+                let records = parts[1].parse::<u32>().ok().filter(|n| *n > 0)?;
+                let interval_ms = parts[2].parse::<u64>().ok()?;
+                Some(Durability::GroupCommit { records, interval_ms })
+            }
+        }
+    }
+}
+
+///
+/// LogConfig
+/// Settings that control how an Operations Log is written
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    pub durability: Durability,
+    pub segment_bytes: u64,     // Start a new segment once this size is reached (0 = never)
+    pub checkpoint_every: u32,  // Checkpoint after this many records (0 = never)
+}
+
+impl LogConfig {
+
+    ///
+    /// from_options(opts)
+    /// The log settings selected on the command line
+    ///
+    pub fn from_options(opts: &TPCOptions) -> LogConfig {
+        LogConfig {
+            format: LogFormat::parse(&opts.log_format).expect("unknown log format"),
+            durability: Durability::parse(&opts.durability).expect("unknown durability"),
+            segment_bytes: parse_size(&opts.segment_size).expect("invalid segment size"),
+            checkpoint_every: opts.checkpoint_interval,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            format: LogFormat::Json,
+            durability: Durability::Flush,
+            segment_bytes: 0,
+            checkpoint_every: 0,
+        }
+    }
+}
+
+///
+/// LogRecord
+/// One entry of an Operations Log: the logged message together with its log
+/// sequence number (the key of the record, local to this log) and the wall
+/// clock time it was appended at
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct LogRecord {
+    pub version: u32,
+    pub lsn: u32,
+    pub timestamp_us: u64,      // Microseconds since the Unix epoch, 0 for version 1 records
+    pub message: message::ProtocolMessage,
+    #[serde(default)]
+    pub prev_hash: String,      // hash() of the previous record, empty for version 1 records
+}
+
+impl LogRecord {
+
+    ///
+    /// hash()
+    /// Hex SHA-256 of the record's bincode encoding, which covers prev_hash
+    /// and so every record before it. Independent of the on-disk format.
+    ///
+    pub fn hash(&self) -> String {
+        let digest = Sha256::digest(&bincode::serialize(self).unwrap());
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+///
+/// parse_size(s)
+/// Parses a byte count with an optional K, M or G suffix, e.g. "64K"
+///
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 1u64 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 1u64 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&s[..i], 1u64 << 30),
+        _ => (s, 1),
+    };
+    digits.parse::<u64>().ok().and_then(|n| n.checked_mul(unit))
+}
+
+///
+/// Checkpoint
+/// Records of the transactions that were still active when the checkpoint
+/// was taken. Recovery starts from these and replays segments from
+/// `segment` onwards; older segments may already be deleted.
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+struct Checkpoint {
+    segment: u32,
+    seqno: u32,
+    records: Vec<LogRecord>,
+    #[serde(default)]
+    chain_head: String,     // Hash of the last record before `segment`
+}
+
+///
+/// OpLogError
+/// Why an Operations Log could not be loaded
+///
+#[derive(Debug)]
+pub enum OpLogError {
+    Io(io::Error),                          // The file could not be read or repaired
+    Corrupt { offset: u64, reason: String }, // A damaged record is followed by more data
+}
+
+impl fmt::Display for OpLogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OpLogError::Io(ref e) => write!(f, "I/O error: {}", e),
+            OpLogError::Corrupt { offset, ref reason } => write!(f, "corrupt record at byte {}: {}", offset, reason),
+        }
+    }
+}
+
+impl error::Error for OpLogError {}
+
+impl From<io::Error> for OpLogError {
+    fn from(e: io::Error) -> OpLogError {
+        OpLogError::Io(e)
+    }
+}
+
+///
+/// TornTail
+/// What was cut off the end of a log whose last write did not complete
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TornTail {
+    pub offset: u64,                // Length of the log after truncation
+    pub discarded_bytes: u64,
+    pub reason: String,
+    pub saved_to: Option<String>,   // Copy of the discarded bytes, None if the log was not repaired
+}
 
 #[derive(Debug)]
 pub struct OpLog {
     seqno: u32,
-    log_arc: Arc<Mutex<HashMap<u32, message::ProtocolMessage>>>,
-    path: String,
-    lf: File,
+    log_arc: Arc<Mutex<HashMap<u32, LogRecord>>>,
+    path: String,                   // Path of segment 0; later segments are "<path>.<n>"
+    lf: Option<BufWriter<File>>,    // Current segment, None for logs loaded with from_file
+    format: LogFormat,
+    durability: Durability,
+    segment_bytes: u64,
+    checkpoint_every: u32,
+    first_segment: u32,             // Oldest segment not yet garbage-collected
+    segment: u32,                   // Segment currently appended to
+    segment_len: u64,
+    since_checkpoint: u32,          // Records appended since the last checkpoint
+    decided: HashSet<String>,       // Transactions decided since the last checkpoint
+    index: Index,
+    last_hash: String,              // hash() of the last record appended
+    unsynced: u32,                  // Records appended since the last sync
+    last_sync: Instant,
+    appends: u64,
+    syncs: u64,
+    checkpoints: u64,
+    torn_tail: Option<TornTail>,
 }
 
 impl OpLog {
 
-    /// 
+    ///
     /// new(fpath: String)
     /// Creates a new Operations Log at the designated file path
     ///
     pub fn new(fpath: String) -> OpLog {
+        OpLog::with_config(fpath, LogConfig::default())
+    }
+
+    ///
+    /// with_config(fpath: String, config: LogConfig)
+    /// Creates a new Operations Log at the designated file path, written in
+    /// the format and with the durability given by the config. Segments and
+    /// checkpoints left at this path by an earlier log are removed.
+    ///
+    pub fn with_config(fpath: String, config: LogConfig) -> OpLog {
         let l = HashMap::new();
         let lck = Mutex::new(l);
         let arc = Arc::new(lck);
+        remove_old_files(&fpath);
+        let (lf, segment_len) = create_segment(&fpath, config.format);
+        let index = Index::default();
+        let last_hash = GENESIS_HASH.to_string();
         OpLog {
             seqno: 0,
             log_arc: arc,
-            path: fpath.to_string(),
-            lf: File::create(fpath).unwrap(),
+            path: fpath,
+            lf: Some(lf),
+            format: config.format,
+            durability: config.durability,
+            segment_bytes: config.segment_bytes,
+            checkpoint_every: config.checkpoint_every,
+            first_segment: 0,
+            segment: 0,
+            segment_len,
+            since_checkpoint: 0,
+            decided: HashSet::new(),
+            index,
+            last_hash,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            appends: 0,
+            syncs: 0,
+            checkpoints: 0,
+            torn_tail: None,
         }
     }
 
     ///
     /// from_file(fpath: String)
     ///
-    /// Reads in and returns an existing Operations Log from the designated file
+    /// Reads in and returns an existing Operations Log from the designated
+    /// file. The format (JSON or binary) is detected from the file contents.
+    /// If the log was checkpointed, recovery starts from the records saved
+    /// in the checkpoint and replays only the segments written after it.
+    ///
+    /// If the last record is incomplete or damaged (a write torn by a crash),
+    /// it is copied to "<segment>.torn", the segment is truncated to the end
+    /// of the last good record, and the discarded tail is reported by
+    /// torn_tail(). A damaged record followed by more data, or a torn record
+    /// in any but the newest segment, fails with OpLogError::Corrupt.
+    ///
+    pub fn from_file(fpath: String) -> Result<OpLog, OpLogError> {
+        OpLog::load(fpath, true)
+    }
+
+    ///
+    /// read_only(fpath: String)
     ///
-    pub fn from_file(fpath: String) -> OpLog {
+    /// Like from_file, but never modifies the log: a torn tail is skipped and
+    /// reported by torn_tail() while the file is left as it is
+    ///
+    pub fn read_only(fpath: String) -> Result<OpLog, OpLogError> {
+        OpLog::load(fpath, false)
+    }
+
+    ///
+    /// load(fpath, repair)
+    ///
+    /// Shared implementation of from_file and read_only
+    ///
+    fn load(fpath: String, repair: bool) -> Result<OpLog, OpLogError> {
         let mut seqno = 0;
         let mut l = HashMap::new();
-        let scopy = fpath.clone();
-        let tlf = File::open(fpath).unwrap();
-        let mut reader = BufReader::new(&tlf);
-        let mut line = String::new();
-        let mut len = reader.read_line(&mut line).unwrap();
-        while len > 0 {
-            let pm = message::ProtocolMessage::from_string(&line);
-            if pm.uid > seqno {
-                seqno = pm.uid;
-            }
-            l.insert(pm.uid, pm);
-            line.clear();
-            len = reader.read_line(&mut line).unwrap();
+        let mut first_segment = 0;
+        let mut last_hash = GENESIS_HASH.to_string();
+        if let Some(ckpt) = read_checkpoint(&fpath)? {
+            seqno = ckpt.seqno;
+            first_segment = ckpt.segment;
+            last_hash = ckpt.chain_head;
+            for rec in ckpt.records {
+                l.insert(rec.lsn, rec);
+            }
+        }
+        let segments = existing_segments(&fpath, first_segment);
+
+        let mut format = LogFormat::Json;
+        let mut torn_tail = None;
+        for (i, seg) in segments.iter().enumerate() {
+            let seg_path = segment_path(&fpath, *seg);
+            let mut data = Vec::new();
+            File::open(&seg_path)?.read_to_end(&mut data)?;
+            let (seg_format, scan) = scan(&data)?;
+            if i == 0 {
+                format = seg_format;
+            }
+            if let Some(reason) = scan.tail {
+                if i + 1 < segments.len() {
+                    return Err(OpLogError::Corrupt {
+                        offset: scan.valid_len,
+                        reason: format!("{} is not the newest segment: {}", seg_path, reason),
+                    });
+                }
+                torn_tail = Some(if repair {
+                    truncate_tail(&seg_path, &data, scan.valid_len, reason)?
+                } else {
+                    TornTail {
+                        offset: scan.valid_len,
+                        discarded_bytes: data.len() as u64 - scan.valid_len,
+                        reason,
+                        saved_to: None,
+                    }
+                });
+            }
+            for mut rec in scan.records {
+                if rec.version == 1 {
+                    rec.lsn = seqno.checked_add(1).ok_or_else(|| OpLogError::Corrupt {
+                        offset: 0,
+                        reason: format!("{}: no LSN left for a version 1 record after LSN {}", seg_path, seqno),
+                    })?;
+                }
+                seqno = seqno.max(rec.lsn);
+                last_hash = rec.hash();
+                l.insert(rec.lsn, rec);
+            }
         }
+        let index = Index::build(&l);
         let lck = Mutex::new(l);
         let arc = Arc::new(lck);
-        OpLog {
-            seqno: seqno,
+        let last = segments.last().cloned().unwrap_or(first_segment);
+        Ok(OpLog {
+            seqno,
             log_arc: arc,
-            path: scopy,
-            lf: tlf,
-        }
+            path: fpath,
+            lf: None,
+            format,
+            durability: Durability::None,
+            segment_bytes: 0,
+            checkpoint_every: 0,
+            first_segment,
+            segment: last,
+            segment_len: 0,
+            since_checkpoint: 0,
+            decided: HashSet::new(),
+            index,
+            last_hash,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            appends: 0,
+            syncs: 0,
+            checkpoints: 0,
+            torn_tail,
+        })
     }
 
     ///
-    /// append(t, tid, sender, op)
+    /// format()
     ///
-    /// Appends an entry to the Operations Log
+    /// On-disk encoding of the log
     ///
-    pub fn append(&mut self, t: message::MessageType, tid: String, sender: String, op: u32) {
-        let lck = Arc::clone(&self.log_arc);
-        let mut log = lck.lock().unwrap();
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    ///
+    /// checkpointed()
+    ///
+    /// Whether the log was checkpointed: the records of transactions decided
+    /// before the last checkpoint are gone, so it no longer holds the whole
+    /// history of the run
+    ///
+    pub fn checkpointed(&self) -> bool {
+        self.first_segment > 0
+    }
+
+    ///
+    /// torn_tail()
+    ///
+    /// What from_file cut off the end of the log, if anything
+    ///
+    pub fn torn_tail(&self) -> Option<&TornTail> {
+        self.torn_tail.as_ref()
+    }
+
+    ///
+    /// append(t, tid, sender, op, lamport)
+    ///
+    /// Appends an entry to the Operations Log, stamped with the Lamport time
+    /// of the logged event. Whether the entry is flushed or synced before
+    /// returning depends on the log's durability; use force() for records
+    /// that must be stable before acting on them.
+    ///
+    pub fn append(&mut self, t: message::MessageType, tid: String, sender: String, op: u32, lamport: u64) {
+        let mut pm = message::ProtocolMessage::generate(t, tid, sender, op);
+        pm.lamport = lamport;
         self.seqno += 1;
-        let id = self.seqno;
-        let pm = message::ProtocolMessage::generate(t, tid, sender, op);
-        serde_json::to_writer(&mut self.lf, &pm).unwrap();
-        writeln!(&mut self.lf).unwrap();
-        self.lf.flush().unwrap();
-        log.insert(id, pm);
+        let rec = LogRecord {
+            version: RECORD_VERSION,
+            lsn: self.seqno,
+            timestamp_us: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0),
+            message: pm,
+            prev_hash: self.last_hash.clone(),
+        };
+        self.last_hash = rec.hash();
+        let record = match self.format {
+            LogFormat::Json => {
+                let mut line = serde_json::to_vec(&rec).unwrap();
+                line.push(b'\n');
+                line
+            },
+            LogFormat::Binary => {
+                let payload = bincode::serialize(&rec).unwrap();
+                let mut record = Vec::with_capacity(BINARY_RECORD_HEADER + payload.len());
+                record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+                record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+                record.extend_from_slice(&payload);
+                record
+            },
+        };
+        self.writer().write_all(&record).unwrap();
+        if t == message::MessageType::CoordinatorCommit || t == message::MessageType::CoordinatorAbort {
+            self.decided.insert(rec.message.txid.clone());
+        }
+        self.index.insert(&rec);
+        self.log_arc.lock().unwrap().insert(rec.lsn, rec);
+        self.segment_len += record.len() as u64;
+        self.appends += 1;
+        self.unsynced += 1;
+        match self.durability {
+            Durability::None => {},
+            Durability::Flush => self.writer().flush().unwrap(),
+            Durability::Fsync => self.sync(),
+            Durability::GroupCommit { records, interval_ms } => {
+                if self.unsynced >= records || self.last_sync.elapsed() >= Duration::from_millis(interval_ms) {
+                    self.sync();
+                }
+            },
+        }
+
+        self.since_checkpoint += 1;
+        if self.checkpoint_every > 0 && self.since_checkpoint >= self.checkpoint_every {
+            self.checkpoint();
+        } else if self.segment_bytes > 0 && self.segment_len >= self.segment_bytes {
+            self.rotate();
+        }
+    }
+
+    ///
+    /// writer()
+    ///
+    /// The current segment; logs loaded with from_file cannot be appended to
+    ///
+    fn writer(&mut self) -> &mut BufWriter<File> {
+        self.lf.as_mut().expect("OpLog loaded with from_file is read-only")
+    }
+
+    ///
+    /// rotate()
+    ///
+    /// Finishes the current segment (synced unless durability is none) and
+    /// continues in a new one
+    ///
+    fn rotate(&mut self) {
+        if self.durability == Durability::None {
+            self.writer().flush().unwrap();
+        } else {
+            self.sync();
+        }
+        self.segment += 1;
+        let (lf, segment_len) = create_segment(&segment_path(&self.path, self.segment), self.format);
+        self.lf = Some(lf);
+        self.segment_len = segment_len;
+    }
+
+    ///
+    /// checkpoint()
+    ///
+    /// Starts a new segment, drops the records of decided transactions from
+    /// memory, saves the records of the still active ones to the checkpoint
+    /// file, and deletes the segments the checkpoint makes redundant.
+    ///
+    pub fn checkpoint(&mut self) {
+        self.rotate();
+        let records = {
+            let mut log = self.log_arc.lock().unwrap();
+            let decided = &self.decided;
+            log.retain(|_, rec| !decided.contains(&rec.message.txid));
+            self.index = Index::build(&log);
+            let mut active: Vec<LogRecord> = log.values().cloned().collect();
+            active.sort_by_key(|rec| rec.lsn);
+            active
+        };
+        self.decided.clear();
+
+        // Write-and-rename so a crash leaves either the old or the new checkpoint
+        let ckpt = Checkpoint { segment: self.segment, seqno: self.seqno, records, chain_head: self.last_hash.clone() };
+        let ckpt_path = checkpoint_path(&self.path);
+        let tmp_path = format!("{}.tmp", ckpt_path);
+        let tmp = File::create(&tmp_path).unwrap();
+        serde_json::to_writer(&tmp, &ckpt).unwrap();
+        if self.durability != Durability::None {
+            tmp.sync_all().unwrap();
+        }
+        fs::rename(&tmp_path, &ckpt_path).unwrap();
+
+        for seg in self.first_segment..self.segment {
+            if let Err(e) = fs::remove_file(segment_path(&self.path, seg)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to remove old log segment {}: {}", segment_path(&self.path, seg), e);
+                }
+            }
+        }
+        self.first_segment = self.segment;
+        self.since_checkpoint = 0;
+        self.checkpoints += 1;
+    }
+
+    ///
+    /// force()
+    ///
+    /// Makes every record appended so far stable (flush and sync_data),
+    /// whatever the group commit schedule. Does nothing if the log was
+    /// configured without durability.
+    ///
+    pub fn force(&mut self) {
+        if self.durability != Durability::None && self.unsynced > 0 {
+            self.sync();
+        }
+    }
+
+    ///
+    /// sync()
+    ///
+    /// Flushes buffered records and waits until they are on stable storage
+    ///
+    fn sync(&mut self) {
+        let lf = self.writer();
+        lf.flush().unwrap();
+        lf.get_ref().sync_data().unwrap();
+        self.syncs += 1;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+    }
+
+    ///
+    /// appends()
+    ///
+    /// Number of records appended so far
+    ///
+    pub fn appends(&self) -> u64 {
+        self.appends
+    }
+
+    ///
+    /// syncs()
+    ///
+    /// Number of times the log was synced to stable storage so far
+    ///
+    pub fn syncs(&self) -> u64 {
+        self.syncs
+    }
+
+    ///
+    /// checkpoints()
+    ///
+    /// Number of checkpoints taken so far
+    ///
+    pub fn checkpoints(&self) -> u64 {
+        self.checkpoints
     }
 
     ///
     /// read(offset)
     ///
-    /// Returns the log entry corresponding with the given offset
+    /// Returns the log entry with the given log sequence number
     ///
-    pub fn read(&mut self, offset: &u32) -> message::ProtocolMessage {
+    pub fn read(&mut self, offset: &u32) -> LogRecord {
         let lck = Arc::clone(&self.log_arc);
         let log = lck.lock().unwrap();
-        let pm = log[&offset].clone();
-        pm
+        log[offset].clone()
+    }
+
+    ///
+    /// iter()
+    ///
+    /// All records in sequence order
+    ///
+    pub fn iter(&self) -> vec::IntoIter<LogRecord> {
+        self.select(&self.index.seqnos)
+    }
+
+    ///
+    /// by_txid(txid)
+    ///
+    /// The history of one transaction, in sequence order
+    ///
+    pub fn by_txid(&self, txid: &str) -> vec::IntoIter<LogRecord> {
+        self.select(self.index.by_txid.get(txid).map_or(&[][..], |k| &k[..]))
+    }
+
+    ///
+    /// by_type(mtype)
+    ///
+    /// All records of one message type, in sequence order
+    ///
+    pub fn by_type(&self, mtype: message::MessageType) -> vec::IntoIter<LogRecord> {
+        self.select(self.index.by_type.get(&mtype).map_or(&[][..], |k| &k[..]))
+    }
+
+    ///
+    /// by_sender(sender)
+    ///
+    /// All records logged on behalf of one sender, in sequence order
+    ///
+    pub fn by_sender(&self, sender: &str) -> vec::IntoIter<LogRecord> {
+        self.select(self.index.by_sender.get(sender).map_or(&[][..], |k| &k[..]))
+    }
+
+    ///
+    /// count(mtype)
+    ///
+    /// Number of records of one message type
+    ///
+    pub fn count(&self, mtype: message::MessageType) -> usize {
+        self.index.by_type.get(&mtype).map_or(0, |k| k.len())
+    }
+
+    ///
+    /// txids()
+    ///
+    /// Every transaction with at least one record, in no particular order
+    ///
+    pub fn txids(&self) -> impl Iterator<Item = &str> {
+        self.index.by_txid.keys().map(|t| t.as_str())
+    }
+
+    ///
+    /// select(keys)
+    ///
+    /// Copies out the records with the given keys, holding the lock once
+    ///
+    fn select(&self, keys: &[u32]) -> vec::IntoIter<LogRecord> {
+        let log = self.log_arc.lock().unwrap();
+        keys.iter().filter_map(|k| log.get(k).cloned()).collect::<Vec<_>>().into_iter()
     }
 
     ///
@@ -103,7 +723,678 @@ impl OpLog {
     ///
     /// Returns an ARC of the mutex locked log
     ///
-    pub fn arc(&self) -> Arc<Mutex<HashMap<u32, message::ProtocolMessage>>> {
+    pub fn arc(&self) -> Arc<Mutex<HashMap<u32, LogRecord>>> {
         Arc::clone(&self.log_arc)
     }
 }
+
+impl Drop for OpLog {
+    ///
+    /// Records still waiting for their group commit are synced on close
+    ///
+    fn drop(&mut self) {
+        if let (Durability::GroupCommit { .. }, Some(lf)) = (self.durability, self.lf.as_mut()) {
+            if self.unsynced > 0 && lf.flush().is_ok() {
+                let _ = lf.get_ref().sync_data();
+            }
+        }
+    }
+}
+
+///
+/// Index
+/// Secondary indexes over the records of an OpLog: record keys in sequence
+/// order, overall and per txid, message type and sender
+///
+#[derive(Debug, Default)]
+struct Index {
+    seqnos: Vec<u32>,
+    by_txid: HashMap<String, Vec<u32>>,
+    by_type: HashMap<message::MessageType, Vec<u32>>,
+    by_sender: HashMap<String, Vec<u32>>,
+}
+
+impl Index {
+
+    ///
+    /// build(log)
+    /// Indexes all records of a log map
+    ///
+    fn build(log: &HashMap<u32, LogRecord>) -> Index {
+        let mut keys: Vec<u32> = log.keys().cloned().collect();
+        keys.sort_unstable();
+        let mut index = Index::default();
+        for k in keys {
+            index.insert(&log[&k]);
+        }
+        index
+    }
+
+    ///
+    /// insert(rec)
+    /// Adds a record; records must be inserted in LSN order
+    ///
+    fn insert(&mut self, rec: &LogRecord) {
+        let pm = &rec.message;
+        self.seqnos.push(rec.lsn);
+        self.by_txid.entry(pm.txid.clone()).or_default().push(rec.lsn);
+        self.by_type.entry(pm.mtype).or_default().push(rec.lsn);
+        self.by_sender.entry(pm.senderid.clone()).or_default().push(rec.lsn);
+    }
+}
+
+///
+/// segment_path(fpath, segment)
+///
+/// File of the given segment of the log at fpath: segment 0 is fpath itself
+/// so that unsegmented logs keep their name, later ones are "<fpath>.<n>"
+///
+pub fn segment_path(fpath: &str, segment: u32) -> String {
+    if segment == 0 {
+        fpath.to_string()
+    } else {
+        format!("{}.{}", fpath, segment)
+    }
+}
+
+///
+/// checkpoint_path(fpath)
+///
+/// File holding the latest checkpoint of the log at fpath
+///
+pub fn checkpoint_path(fpath: &str) -> String {
+    format!("{}.checkpoint", fpath)
+}
+
+///
+/// remove_old_files(fpath)
+///
+/// Deletes what an earlier log at fpath left next to it: every segment
+/// "<fpath>.<n>" (numbering may have gaps once checkpoints garbage-collected
+/// old segments), the checkpoint and its temporary file, and saved torn tails
+///
+fn remove_old_files(fpath: &str) {
+    let path = Path::new(fpath);
+    let (dir, name) = match (path.parent(), path.file_name().and_then(|n| n.to_str())) {
+        (Some(dir), Some(name)) => (if dir.as_os_str().is_empty() { Path::new(".") } else { dir }, name),
+        _ => return,
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let prefix = format!("{}.", name);
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let suffix = match file_name.to_str().and_then(|f| f.strip_prefix(&prefix)) {
+            Some(suffix) => suffix,
+            None => continue,
+        };
+        let segment = suffix.strip_suffix(".torn").unwrap_or(suffix);
+        let stale = segment == "checkpoint" || segment == "checkpoint.tmp" || segment == "torn"
+            || (!segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()));
+        if stale {
+            if let Err(e) = fs::remove_file(entry.path()) {
+                warn!("Failed to remove old log file {}: {}", entry.path().display(), e);
+            }
+        }
+    }
+}
+
+///
+/// read_checkpoint(fpath)
+///
+/// The latest checkpoint of the log at fpath, None if it has none. Fails
+/// with the usual not-found error if there is no log at all.
+///
+fn read_checkpoint(fpath: &str) -> Result<Option<Checkpoint>, OpLogError> {
+    let ckpt_path = checkpoint_path(fpath);
+    if !Path::new(&ckpt_path).exists() {
+        File::open(fpath)?;
+        return Ok(None);
+    }
+    serde_json::from_reader(io::BufReader::new(File::open(&ckpt_path)?))
+        .map(Some)
+        .map_err(|e| OpLogError::Corrupt { offset: 0, reason: format!("{}: {}", ckpt_path, e) })
+}
+
+///
+/// existing_segments(fpath, first)
+///
+/// Consecutive segments of the log at fpath that exist, starting at first
+///
+fn existing_segments(fpath: &str, first: u32) -> Vec<u32> {
+    let mut segments = Vec::new();
+    while Path::new(&segment_path(fpath, first + segments.len() as u32)).exists() {
+        segments.push(first + segments.len() as u32);
+    }
+    segments
+}
+
+///
+/// ChainBreak
+/// The first record whose prev_hash does not match its predecessor
+///
+#[derive(Clone, Debug)]
+pub struct ChainBreak {
+    pub segment: u32,
+    pub lsn: u32,
+    pub expected: String,   // hash() of the preceding record
+    pub found: String,      // prev_hash stored in the record
+}
+
+///
+/// ChainReport
+/// Outcome of walking the hash chain of a log with verify_chain
+///
+#[derive(Clone, Debug)]
+pub struct ChainReport {
+    pub records: u64,           // Records checked
+    pub unchained: u64,         // Version 1 records, which carry no hash
+    pub torn_tail: Option<String>,
+    pub broken: Option<ChainBreak>,
+}
+
+///
+/// verify_chain(fpath)
+///
+/// Walks every record of the log at fpath in file order, from the oldest
+/// segment still on disk, and checks that each record's prev_hash is the
+/// hash of the record before it and that LSNs have no gaps. Unlike
+/// from_file this never repairs the log; a torn tail is only reported.
+///
+/// Editing, inserting, deleting or reordering records breaks the chain at
+/// the first changed record. Truncating whole records off the end does not;
+/// compare the last LSN with an external record to detect that.
+///
+pub fn verify_chain(fpath: &str) -> Result<ChainReport, OpLogError> {
+    let (first_segment, mut expected) = match read_checkpoint(fpath)? {
+        Some(ckpt) => (ckpt.segment, ckpt.chain_head),
+        None => (0, GENESIS_HASH.to_string()),
+    };
+    let mut report = ChainReport { records: 0, unchained: 0, torn_tail: None, broken: None };
+    let mut last_lsn = None;
+    for seg in existing_segments(fpath, first_segment) {
+        let mut data = Vec::new();
+        File::open(segment_path(fpath, seg))?.read_to_end(&mut data)?;
+        let (_, scan) = scan(&data)?;
+        if scan.tail.is_some() {
+            report.torn_tail = scan.tail;
+        }
+        for rec in scan.records {
+            report.records += 1;
+            if rec.version == 1 {
+                report.unchained += 1;
+                expected = rec.hash();
+                continue;
+            }
+            let gap = last_lsn.is_some_and(|l: u32| l.checked_add(1) != Some(rec.lsn));
+            if rec.prev_hash != expected || gap {
+                report.broken = Some(ChainBreak { segment: seg, lsn: rec.lsn, expected, found: rec.prev_hash.clone() });
+                return Ok(report);
+            }
+            last_lsn = Some(rec.lsn);
+            expected = rec.hash();
+        }
+    }
+    Ok(report)
+}
+
+///
+/// create_segment(path, format)
+///
+/// Creates an empty segment file, writing the binary header if needed.
+/// Returns the writer and the number of bytes already in the file.
+///
+fn create_segment(path: &str, format: LogFormat) -> (BufWriter<File>, u64) {
+    let mut lf = BufWriter::new(File::create(path).unwrap());
+    if format == LogFormat::Binary {
+        lf.write_all(BINARY_MAGIC).unwrap();
+        lf.flush().unwrap();
+        (lf, BINARY_MAGIC.len() as u64)
+    } else {
+        (lf, 0)
+    }
+}
+
+///
+/// parse_segment(data)
+///
+/// Parses the raw bytes of one log segment the way from_file does, without
+/// touching the filesystem. Returns the records read and, if the segment
+/// ends in a torn record, why it was rejected. Version 1 records keep LSN 0.
+///
+pub fn parse_segment(data: &[u8]) -> Result<(Vec<LogRecord>, Option<String>), OpLogError> {
+    let (_, scan) = scan(data)?;
+    Ok((scan.records, scan.tail))
+}
+
+///
+/// Scan
+/// Result of parsing the raw bytes of a log
+///
+struct Scan {
+    records: Vec<LogRecord>,
+    valid_len: u64,         // Bytes up to the end of the last good record
+    tail: Option<String>,   // Why the bytes after valid_len were rejected
+}
+
+///
+/// scan(data)
+///
+/// Detects the format of a log and parses it: binary logs begin with
+/// BINARY_MAGIC (or, after a crash while creating the file, a prefix of
+/// it); anything else is read as JSON lines.
+///
+fn scan(data: &[u8]) -> Result<(LogFormat, Scan), OpLogError> {
+    if data.starts_with(BINARY_MAGIC) {
+        Ok((LogFormat::Binary, scan_binary(data, decode_binary)?))
+    } else if !data.is_empty() && BINARY_MAGIC.starts_with(data) {
+        let tail = Some("incomplete file header".to_string());
+        Ok((LogFormat::Binary, Scan { records: Vec::new(), valid_len: 0, tail }))
+    } else {
+        Ok((LogFormat::Json, scan_json(data)?))
+    }
+}
+
+///
+/// scan_json(data)
+///
+/// Parses a JSON lines log. A line that does not parse is a torn tail if
+/// nothing but whitespace follows it.
+///
+fn scan_json(data: &[u8]) -> Result<Scan, OpLogError> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let end = data[pos..].iter().position(|b| *b == b'\n').map(|i| pos + i + 1).unwrap_or(data.len());
+        let line = &data[pos..end];
+        let parsed = std::str::from_utf8(line)
+            .map_err(|e| e.to_string())
+            .and_then(|l| if l.trim().is_empty() { Ok(None) } else {
+                decode_json(l).map(Some)
+            });
+        match parsed {
+            Ok(Some(rec)) => records.push(rec),
+            Ok(None) => {},
+            Err(reason) => {
+                if data[end..].iter().all(|b| b.is_ascii_whitespace()) {
+                    return Ok(Scan { records, valid_len: pos as u64, tail: Some(reason) });
+                }
+                return Err(OpLogError::Corrupt { offset: pos as u64, reason });
+            }
+        }
+        pos = end;
+    }
+    Ok(Scan { records, valid_len: data.len() as u64, tail: None })
+}
+
+///
+/// scan_binary(data, decode)
+///
+/// Parses a binary log, verifying each record's CRC32 and decoding payloads
+/// with the given function. A damaged last record, including one that runs
+/// past the end of the file, is a torn tail, unless its payload starts with
+/// a shorter record its CRC matches: then the length is damaged. A record
+/// cut short never holds a whole one, so a truncated log always loads.
+///
+fn scan_binary(data: &[u8], decode: fn(&[u8]) -> Result<LogRecord, String>) -> Result<Scan, OpLogError> {
+    let mut records = Vec::new();
+    let mut pos = BINARY_MAGIC.len();
+    while pos < data.len() {
+        let torn = |reason: &str, records| Ok(Scan { records, valid_len: pos as u64, tail: Some(reason.to_string()) });
+        if data.len() - pos < BINARY_RECORD_HEADER {
+            return torn("truncated record header", records);
+        }
+        let h = &data[pos..pos + BINARY_RECORD_HEADER];
+        let len = u32::from_le_bytes([h[0], h[1], h[2], h[3]]) as usize;
+        let crc = u32::from_le_bytes([h[4], h[5], h[6], h[7]]);
+        let start = pos + BINARY_RECORD_HEADER;
+        let end = data.len().min(start + len);
+        let payload = &data[start..end];
+        let reason = if end < start + len {
+            Some("truncated record payload".to_string())
+        } else if crc32fast::hash(payload) != crc {
+            Some("CRC mismatch".to_string())
+        } else {
+            match decode(payload) {
+                Ok(rec) => {
+                    records.push(rec);
+                    None
+                },
+                Err(e) => Some(e),
+            }
+        };
+        if let Some(reason) = reason {
+            if let Some(size) = record_at(payload, crc, decode) {
+                return Err(OpLogError::Corrupt {
+                    offset: pos as u64,
+                    reason: format!("record length {} does not match the {}-byte record it holds", len, size),
+                });
+            }
+            if end == data.len() {
+                return torn(&reason, records);
+            }
+            return Err(OpLogError::Corrupt { offset: pos as u64, reason });
+        }
+        pos = end;
+    }
+    Ok(Scan { records, valid_len: data.len() as u64, tail: None })
+}
+
+///
+/// record_at(bytes, crc, decode)
+///
+/// Size of the record that `bytes` begin with, if they hold a whole one
+/// whose CRC32 is `crc` and which decodes. Reads only that record, so
+/// checking a damaged length costs no more than reading it.
+///
+fn record_at(bytes: &[u8], crc: u32, decode: fn(&[u8]) -> Result<LogRecord, String>) -> Option<usize> {
+    let rec: LogRecord = bincode::deserialize(bytes).ok()?;
+    let size = bincode::serialized_size(&rec).ok()? as usize;
+    if size <= bytes.len() && crc32fast::hash(&bytes[..size]) == crc && decode(&bytes[..size]).is_ok() {
+        Some(size)
+    } else {
+        None
+    }
+}
+
+///
+/// decode_json(line)
+///
+/// Parses one JSON line: a LogRecord, or a bare version 1 ProtocolMessage
+/// whose LSN is assigned by from_file
+///
+fn decode_json(line: &str) -> Result<LogRecord, String> {
+    let value: serde_json::Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    if value.get("version").is_none() {
+        let pm = message::ProtocolMessage::from_string(line).map_err(|e| e.to_string())?;
+        return Ok(LogRecord { version: 1, lsn: 0, timestamp_us: 0, message: pm, prev_hash: String::new() });
+    }
+    let rec: LogRecord = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    check_version(rec)
+}
+
+///
+/// decode_binary(payload)
+///
+/// Decodes the payload of a record in a current binary log, which must hold
+/// exactly one record
+///
+fn decode_binary(payload: &[u8]) -> Result<LogRecord, String> {
+    let rec: LogRecord = bincode::deserialize(payload).map_err(|e| e.to_string())?;
+    let size = bincode::serialized_size(&rec).map_err(|e| e.to_string())? as usize;
+    if size != payload.len() {
+        return Err(format!("{} bytes after the record", payload.len() - size));
+    }
+    check_version(rec)
+}
+
+///
+/// check_version(rec)
+///
+/// Rejects records written with a newer schema than this build understands,
+/// and version 2 and 3 records, which were never released
+///
+fn check_version(rec: LogRecord) -> Result<LogRecord, String> {
+    if rec.version != RECORD_VERSION {
+        return Err(format!("unsupported record version {}", rec.version));
+    }
+    Ok(rec)
+}
+
+///
+/// truncate_tail(fpath, data, valid_len, reason)
+///
+/// Saves the bytes after valid_len to "<fpath>.torn", then truncates the log
+/// to valid_len and syncs it, so the damaged tail is never read again but
+/// can still be inspected.
+///
+fn truncate_tail(fpath: &str, data: &[u8], valid_len: u64, reason: String) -> Result<TornTail, OpLogError> {
+    let saved_to = format!("{}.torn", fpath);
+    let mut saved = File::create(&saved_to)?;
+    saved.write_all(&data[valid_len as usize..])?;
+    saved.sync_all()?;
+
+    let lf = OpenOptions::new().write(true).open(fpath)?;
+    lf.set_len(valid_len)?;
+    lf.sync_all()?;
+
+    warn!("{}: discarded torn tail of {} bytes at offset {} ({}), saved to {}",
+          fpath, data.len() as u64 - valid_len, valid_len, reason, saved_to);
+    let tail = TornTail {
+        offset: valid_len,
+        discarded_bytes: data.len() as u64 - valid_len,
+        reason,
+        saved_to: Some(saved_to),
+    };
+    Ok(tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use message::MessageType;
+    use super::*;
+
+    /// Numbers the scratch directories of the tests of this process
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Path of a log in a fresh, empty scratch directory
+    fn scratch_log() -> String {
+        let dir = std::env::temp_dir().join(format!("2pc-oplog-{}-{}", process::id(), DIRS.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("test.log").to_string_lossy().into_owned()
+    }
+
+    /// Appends a decided transaction: proposal, vote and commit
+    fn append_tx(log: &mut OpLog, txid: &str) {
+        log.append(MessageType::CoordinatorPropose, txid.to_string(), "coordinator".to_string(), 0, 1);
+        log.append(MessageType::ParticipantVoteCommit, txid.to_string(), "participant_0".to_string(), 0, 2);
+        log.append(MessageType::CoordinatorCommit, txid.to_string(), "coordinator".to_string(), 0, 3);
+    }
+
+    #[test]
+    fn hash_chain_links_every_record() {
+        let path = scratch_log();
+        {
+            let mut log = OpLog::new(path.clone());
+            for i in 0..3 {
+                append_tx(&mut log, &format!("tx_{}", i));
+            }
+        }
+        let report = verify_chain(&path).unwrap();
+        assert_eq!((report.records, report.unchained), (9, 0));
+        assert!(report.broken.is_none() && report.torn_tail.is_none());
+
+        let records: Vec<LogRecord> = OpLog::from_file(path.clone()).unwrap().iter().collect();
+        assert_eq!(records[0].prev_hash, GENESIS_HASH);
+        for pair in records.windows(2) {
+            assert_eq!(pair[1].prev_hash, pair[0].hash());
+        }
+
+        // Editing a record breaks the link to the record after it
+        let edited = fs::read_to_string(&path).unwrap().replacen("participant_0", "participant_9", 1);
+        fs::write(&path, edited).unwrap();
+        let broken = verify_chain(&path).unwrap().broken.expect("edit not detected");
+        assert_eq!(broken.lsn, 3);
+    }
+
+    #[test]
+    fn rotated_segments_reload_in_order() {
+        let path = scratch_log();
+        {
+            let mut log = OpLog::with_config(path.clone(), LogConfig { segment_bytes: 600, ..LogConfig::default() });
+            for i in 0..4 {
+                append_tx(&mut log, &format!("tx_{}", i));
+            }
+        }
+        assert!(Path::new(&segment_path(&path, 2)).exists());
+        assert!(verify_chain(&path).unwrap().broken.is_none());
+
+        let log = OpLog::from_file(path).unwrap();
+        let lsns: Vec<u32> = log.iter().map(|rec| rec.lsn).collect();
+        assert_eq!(lsns, (1..=12).collect::<Vec<u32>>());
+        let txids: Vec<String> = log.iter().map(|rec| rec.message.txid).collect();
+        assert_eq!(&txids[3..6], &["tx_1"; 3]);
+        assert_eq!(txids[11], "tx_3");
+        assert_eq!(log.count(MessageType::CoordinatorCommit), 4);
+        assert_eq!(log.by_txid("tx_2").count(), 3);
+        assert_eq!(log.by_sender("participant_0").count(), 4);
+    }
+
+    #[test]
+    fn new_log_removes_segments_left_after_checkpoints() {
+        let path = scratch_log();
+        let config = LogConfig { checkpoint_every: 3, ..LogConfig::default() };
+        {
+            let mut old = OpLog::with_config(path.clone(), config);
+            for i in 0..5 {
+                append_tx(&mut old, &format!("old_{}", i));
+            }
+            assert_eq!(old.checkpoints(), 5);
+        }
+        fs::write(format!("{}.4.torn", path), b"x").unwrap();
+        fs::write(format!("{}.checkpoint.tmp", path), b"x").unwrap();
+        assert!(!Path::new(&segment_path(&path, 1)).exists());
+        assert!(Path::new(&segment_path(&path, 5)).exists());
+
+        {
+            let mut new = OpLog::with_config(path.clone(), LogConfig::default());
+            append_tx(&mut new, "new_0");
+        }
+        let dir = Path::new(&path).parent().unwrap();
+        let left: Vec<String> = fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(left, vec!["test.log".to_string()]);
+
+        let reloaded = OpLog::from_file(path).unwrap();
+        let txids: Vec<String> = reloaded.iter().map(|rec| rec.message.txid).collect();
+        assert_eq!(txids, vec!["new_0"; 3]);
+    }
+
+    #[test]
+    fn checkpointed_logs_are_reported_after_reload() {
+        let path = scratch_log();
+        {
+            let mut log = OpLog::with_config(path.clone(), LogConfig::default());
+            append_tx(&mut log, "tx_0");
+            assert!(!log.checkpointed());
+        }
+        assert!(!OpLog::from_file(path.clone()).unwrap().checkpointed());
+
+        {
+            let mut log = OpLog::with_config(path.clone(), LogConfig { checkpoint_every: 3, ..LogConfig::default() });
+            append_tx(&mut log, "tx_0");
+            log.append(MessageType::CoordinatorPropose, "tx_1".to_string(), "coordinator".to_string(), 0, 4);
+            assert!(log.checkpointed());
+        }
+        let reloaded = OpLog::from_file(path).unwrap();
+        assert!(reloaded.checkpointed());
+        let txids: Vec<String> = reloaded.iter().map(|rec| rec.message.txid).collect();
+        assert_eq!(txids, vec!["tx_1"]);
+    }
+
+    /// A binary log of three decided transactions and the offsets its records start at
+    fn binary_log() -> (String, Vec<u8>, Vec<usize>) {
+        let path = scratch_log();
+        {
+            let mut log = OpLog::with_config(path.clone(), LogConfig { format: LogFormat::Binary, ..LogConfig::default() });
+            for i in 0..3 {
+                append_tx(&mut log, &format!("tx_{}", i));
+            }
+        }
+        let data = fs::read(&path).unwrap();
+        let mut offsets = Vec::new();
+        let mut pos = BINARY_MAGIC.len();
+        while pos < data.len() {
+            offsets.push(pos);
+            pos += BINARY_RECORD_HEADER + u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        }
+        (path, data, offsets)
+    }
+
+    #[test]
+    fn torn_binary_tail_is_truncated() {
+        let (path, data, offsets) = binary_log();
+        let cut = offsets[8] + BINARY_RECORD_HEADER + 3;
+        fs::write(&path, &data[..cut]).unwrap();
+
+        let log = OpLog::from_file(path.clone()).unwrap();
+        assert_eq!(log.iter().count(), 8);
+        let tail = log.torn_tail().unwrap();
+        assert_eq!(tail.offset, offsets[8] as u64);
+        assert_eq!(tail.reason, "truncated record payload");
+        assert_eq!(fs::read(&path).unwrap(), &data[..offsets[8]]);
+    }
+
+    #[test]
+    fn damaged_length_mid_file_is_corrupt() {
+        let (path, mut data, offsets) = binary_log();
+        let len = data.len() as u32;
+        data[offsets[4]..offsets[4] + 4].copy_from_slice(&len.to_le_bytes());
+        fs::write(&path, &data).unwrap();
+
+        match OpLog::from_file(path.clone()) {
+            Err(OpLogError::Corrupt { offset, .. }) => assert_eq!(offset, offsets[4] as u64),
+            other => panic!("expected a corrupt log, got {:?}", other.map(|l| l.iter().count())),
+        }
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(!Path::new(&format!("{}.torn", path)).exists());
+    }
+
+    #[test]
+    fn every_truncation_of_a_binary_log_is_torn() {
+        let (_, data, offsets) = binary_log();
+        for cut in BINARY_MAGIC.len()..data.len() {
+            let (records, tail) = parse_segment(&data[..cut]).unwrap();
+            assert_eq!(records.len(), offsets[1..].iter().filter(|&&end| end <= cut).count());
+            assert_eq!(tail.is_some(), !offsets.contains(&cut));
+        }
+    }
+
+    #[test]
+    fn damaged_crc_is_torn_only_at_the_end() {
+        let (_, mut data, offsets) = binary_log();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        let (records, tail) = parse_segment(&data).unwrap();
+        assert_eq!(records.len(), 8);
+        assert_eq!(tail.as_deref(), Some("CRC mismatch"));
+
+        data[last] ^= 0xff;
+        data[offsets[2] + BINARY_RECORD_HEADER] ^= 0xff;
+        match parse_segment(&data) {
+            Err(OpLogError::Corrupt { offset, .. }) => assert_eq!(offset, offsets[2] as u64),
+            other => panic!("expected a corrupt segment, got {:?}", other.map(|(r, t)| (r.len(), t))),
+        }
+    }
+
+    #[test]
+    fn json_tail_is_torn_only_if_nothing_follows() {
+        let path = scratch_log();
+        {
+            let mut log = OpLog::new(path.clone());
+            append_tx(&mut log, "tx_0");
+        }
+        let mut data = fs::read(&path).unwrap();
+        let good = data.len();
+        data.extend_from_slice(b"{\"version\":4,\"ls");
+        let (records, tail) = parse_segment(&data).unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(tail.is_some());
+
+        data.extend_from_slice(b"\n");
+        data.extend_from_slice(&fs::read(&path).unwrap());
+        match parse_segment(&data) {
+            Err(OpLogError::Corrupt { offset, .. }) => assert_eq!(offset, good as u64),
+            other => panic!("expected a corrupt segment, got {:?}", other.map(|(r, t)| (r.len(), t))),
+        }
+    }
+}
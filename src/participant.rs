@@ -7,7 +7,9 @@ extern crate log;
 extern crate rand;
 extern crate stderrlog;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
@@ -20,16 +22,23 @@ use participant::ipc_channel::ipc::IpcReceiver as Receiver;
 use participant::ipc_channel::ipc::TryRecvError;
 use participant::ipc_channel::ipc::IpcSender as Sender;
 
+use bank::{Bank, BankSnapshot};
+use clock::LamportClock;
 use message::MessageType;
 use message::ProtocolMessage;
 use message::RequestStatus;
+use metrics::MetricsPublisher;
 use oplog;
+use protocol::{transition_key, ParticipantAction, ParticipantInput, REJECTED};
+use stats::{LatencyStats, StatsReport};
+use trace::Tracer;
+use tracing::{event, span, Level};
 
 ///
 /// ParticipantState
 /// enum for Participant 2PC state machine
 ///
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ParticipantState {
     Quiescent,
     ReceivedP1,
@@ -38,6 +47,17 @@ pub enum ParticipantState {
     AwaitingGlobalDecision,
 }
 
+impl ParticipantState {
+    /// Every state, in protocol order
+    pub const ALL: [ParticipantState; 5] = [
+        ParticipantState::Quiescent,
+        ParticipantState::ReceivedP1,
+        ParticipantState::VotedAbort,
+        ParticipantState::VotedCommit,
+        ParticipantState::AwaitingGlobalDecision,
+    ];
+}
+
 ///
 /// Participant
 /// Structure for maintaining per-participant state and communication/synchronization objects to/from coordinator
@@ -46,12 +66,23 @@ pub enum ParticipantState {
 pub struct Participant {
     id_str: String,
     state: ParticipantState,
+    txns: HashMap<String, ParticipantState>,
+    finished: u64,                              // Transactions back in Quiescent, removed from txns
     log: oplog::OpLog,
     running: Arc<AtomicBool>,
     send_success_prob: f64,
     operation_success_prob: f64,
     tx: Sender<ProtocolMessage>,
     rx: Receiver<ProtocolMessage>,
+    bank: Option<Bank>,
+    stats: LatencyStats,
+    tracer: Tracer,
+    clock: LamportClock,
+    metrics: Option<MetricsPublisher>,
+    rng: StdRng,
+    send_failures: u32,
+    illegal_transitions: u32,
+    transitions: BTreeMap<String, u64>,
     abort: u32,
     commit: u32,
     unknown: u32
@@ -79,25 +110,40 @@ impl Participant {
     ///       the protocol is still running to this constructor. There are other
     ///       ways to communicate this, of course.
     ///
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id_str: String,
         log_path: String,
+        log_config: oplog::LogConfig,
         r: Arc<AtomicBool>,
         send_success_prob: f64,
         operation_success_prob: f64,
         tx: Sender<ProtocolMessage>,
-        rx: Receiver<ProtocolMessage>) -> Participant {
+        rx: Receiver<ProtocolMessage>,
+        bank: Option<Bank>) -> Participant {
 
+        let tracer = Tracer::new(&id_str);
         Participant {
             id_str: id_str,
             state: ParticipantState::Quiescent,
-            log: oplog::OpLog::new(log_path),
+            txns: HashMap::new(),
+            finished: 0,
+            log: oplog::OpLog::with_config(log_path, log_config),
             running: r,
             send_success_prob: send_success_prob,
             operation_success_prob: operation_success_prob,
             // TODO
             tx,
             rx,
+            bank,
+            stats: LatencyStats::new(),
+            tracer,
+            clock: LamportClock::new(),
+            metrics: None,
+            rng: StdRng::from_entropy(),
+            send_failures: 0,
+            illegal_transitions: 0,
+            transitions: BTreeMap::new(),
             abort : 0,
             commit: 0,
             unknown: 0
@@ -105,6 +151,15 @@ impl Participant {
     }
 
 
+    ///
+    /// seed()
+    /// Make the participant's operation outcomes and message drops
+    /// reproducible
+    ///
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
     ///
     /// send()
     /// Send a protocol message to the coordinator. This can fail depending on
@@ -115,21 +170,22 @@ impl Participant {
     ///
 
     pub fn send(&mut self, pm: ProtocolMessage)  {
-    let mut rng = rand::thread_rng();  // Get a random number generator
-    let x: f64 = rng.gen();
-    let mut mes = pm.clone();
+    let x: f64 = self.rng.gen();
+    let mut mes = pm;
     mes.senderid = self.id_str.clone();
     if  x <= self.send_success_prob {
-        if pm.mtype == MessageType::ParticipantVoteCommit{
+        if mes.mtype == MessageType::ParticipantVoteCommit{
             self.commit += 1;
         } else {
             self.abort += 1;
         }
-        let _ = self.tx
-            .send(pm)
-            .map_err(|e| format!("Failed to send message: {}", e));
+        if let Err(e) = self.tx.send(mes) {
+            self.send_failures += 1;
+            event!(Level::ERROR, participant = %self.id_str, error = %e, "failed to send vote");
+        }
     } else {
         self.unknown+=1;
+        self.send_failures += 1;
     }
 }
 
@@ -150,17 +206,8 @@ impl Participant {
     pub fn perform_operation(&mut self, request_option: Option<ProtocolMessage>) -> bool {
         trace!("{}::Performing operation", self.id_str.clone());
         if let Some(message) = request_option {
-            let mut rng = rand::thread_rng();  // Get a random number generator
-            let x: f64 = rng.gen();
-            if x <= self.operation_success_prob {
-                self.log.append(MessageType::ParticipantVoteCommit, message.txid.clone(), message.senderid.clone(), message.opid);
-                true
-            } else {
-                // Log failure, take necessary steps for operation failure.
-                // self.log.log_failure(&request);
-                self.log.append(MessageType::ParticipantVoteAbort, message.txid.clone(), message.senderid.clone(), message.opid);
-                false
-            }
+            let x: f64 = self.rng.gen();
+            x <= self.operation_success_prob && self.prepare_transfer(&message)
         } else {
             // If there is no operation request, do nothing and return false.
             false
@@ -168,6 +215,105 @@ impl Participant {
     }
 
 
+    ///
+    /// step(message, input)
+    /// Feed input to the state machine of message's transaction and carry
+    /// out the resulting actions. An illegal transition (e.g. a commit for a
+    /// transaction this participant voted to abort) is logged, counted and
+    /// rejected: the state stays as it was, nothing is done, and false is
+    /// returned.
+    ///
+    fn step(&mut self, message: &ProtocolMessage, input: ParticipantInput) -> bool {
+        let state = self.txn_state(&message.txid);
+        let actions = match state.step(input) {
+            Ok((next, actions)) => {
+                event!(Level::DEBUG, phase = "transition", participant = %self.id_str, from = ?state, to = ?next, input = ?input, "state transition");
+                let key = transition_key(&format!("{:?}", state), &input.to_string(), &format!("{:?}", next));
+                *self.transitions.entry(key).or_insert(0) += 1;
+                self.state = next;
+                if next == ParticipantState::Quiescent {
+                    self.txns.remove(&message.txid);
+                    self.finished += 1;
+                } else {
+                    self.txns.insert(message.txid.clone(), next);
+                }
+                actions
+            },
+            Err(illegal) => {
+                self.illegal_transitions += 1;
+                let key = transition_key(&format!("{:?}", illegal.state), &input.to_string(), REJECTED);
+                *self.transitions.entry(key).or_insert(0) += 1;
+                event!(Level::ERROR, phase = "transition", participant = %self.id_str, state = ?illegal.state, input = ?illegal.input, "rejected {}", illegal);
+                return false;
+            },
+        };
+        for action in actions {
+            match action {
+                ParticipantAction::LogVote(commit) => {
+                    // The vote is forced to stable storage before it is sent
+                    let vote = if commit { MessageType::ParticipantVoteCommit } else { MessageType::ParticipantVoteAbort };
+                    let voted = self.clock.tick();
+                    self.log.append(vote, message.txid.clone(), message.senderid.clone(), message.opid, voted);
+                    self.log.force();
+                },
+                ParticipantAction::SendVote(commit) => {
+                    let mut mes = message.clone();
+                    mes.mtype = if commit { MessageType::ParticipantVoteCommit } else { MessageType::ParticipantVoteAbort };
+                    mes.lamport = self.clock.time();
+                    event!(Level::INFO, phase = "vote", participant = %self.id_str, vote = ?mes.mtype, lamport = mes.lamport, "voted");
+                    self.send(mes);
+                },
+                ParticipantAction::Apply(commit) => {
+                    if let Some(bank) = self.bank.as_mut() {
+                        if commit {
+                            bank.commit(&message.txid);
+                        } else {
+                            bank.abort(&message.txid);
+                        }
+                    }
+                },
+            }
+        }
+        true
+    }
+
+    ///
+    /// state()
+    /// State the last transition left this participant's latest transaction in
+    ///
+    pub fn state(&self) -> ParticipantState {
+        self.state
+    }
+
+    ///
+    /// txn_state(txid)
+    /// Where txid is in the state machine; Quiescent if it is not in progress
+    ///
+    pub fn txn_state(&self, txid: &str) -> ParticipantState {
+        self.txns.get(txid).cloned().unwrap_or(ParticipantState::Quiescent)
+    }
+
+    ///
+    /// prepare_transfer
+    /// For the bank workload, checks and holds this participant's half of
+    /// the transfer. Always succeeds for other workloads.
+    ///
+    fn prepare_transfer(&mut self, message: &ProtocolMessage) -> bool {
+        match (self.bank.as_mut(), message.transfer.as_ref()) {
+            (Some(bank), Some(transfer)) => bank.prepare(&message.txid, transfer),
+            _ => true,
+        }
+    }
+
+    ///
+    /// bank_snapshot()
+    /// Final balances of this participant's accounts, if it runs the bank
+    /// workload
+    ///
+    pub fn bank_snapshot(&self) -> Option<BankSnapshot> {
+        self.bank.as_ref().map(|b| b.snapshot())
+    }
+
     ///
     /// report_status()
     /// Report the abort/commit/unknown status (aggregate) of all transaction
@@ -177,6 +323,68 @@ impl Participant {
 
     pub fn report_status(&mut self) {
         println!("{:16}:\tCommitted: {:6}\tAborted: {:6}\tUnknown: {:6}", self.id_str.clone(), self.commit, self.abort, self.unknown);
+        self.stats_report().print();
+    }
+
+    ///
+    /// stats_report()
+    /// Counters, latency histogram of handling a proposal (operation plus
+    /// vote), and the participant's throughput in votes per second
+    ///
+    pub fn stats_report(&self) -> StatsReport {
+        let counters = [
+            ("commit", self.commit),
+            ("abort", self.abort),
+            ("unknown", self.unknown),
+            ("send_failures", self.send_failures),
+            ("illegal_transitions", self.illegal_transitions),
+            ("log_appends", self.log.appends() as u32),
+            ("log_syncs", self.log.syncs() as u32),
+            ("log_checkpoints", self.log.checkpoints() as u32),
+        ];
+        let voted = (self.commit + self.abort + self.unknown) as u64;
+        let mut report = self.stats.report(&self.id_str, &counters, voted);
+        report.state = format!("{:?}", self.state);
+        report.states = ParticipantState::ALL.iter()
+            .map(|s| {
+                let in_progress = self.txns.values().filter(|t| *t == s).count() as u64;
+                let finished = if *s == ParticipantState::Quiescent { self.finished } else { 0 };
+                (format!("{:?}", s), in_progress + finished)
+            })
+            .collect();
+        report.transitions = self.transitions.clone();
+        report
+    }
+
+    ///
+    /// tracer()
+    /// Trace events recorded so far
+    ///
+    pub fn tracer(&self) -> &Tracer {
+        &self.tracer
+    }
+
+    ///
+    /// serve_metrics()
+    /// Expose this participant's counters and latency histograms on addr
+    ///
+    pub fn serve_metrics(&mut self, addr: SocketAddr) -> io::Result<()> {
+        self.metrics = Some(MetricsPublisher::start(addr, &self.id_str)?);
+        self.publish_metrics(true);
+        Ok(())
+    }
+
+    ///
+    /// publish_metrics()
+    /// Refresh the metrics endpoint, at most every few hundred ms unless forced
+    ///
+    fn publish_metrics(&mut self, force: bool) {
+        if self.metrics.as_ref().is_some_and(|m| force || m.due()) {
+            let report = self.stats_report().without_samples();
+            if let Some(metrics) = self.metrics.as_mut() {
+                metrics.publish(report);
+            }
+        }
     }
 
 
@@ -213,6 +421,7 @@ impl Participant {
         // TODO
         let timeout_duration = Duration::from_secs(3);
         let mut start = Instant::now();
+        self.stats.restart_clock();
         loop {
             if !self.running.load(Ordering::SeqCst) {
                 trace!("{}::Exiting", self.id_str.clone());
@@ -220,32 +429,49 @@ impl Participant {
             }
             match self.rx.recv() {
                 Ok(message) => {
+                    let received = self.clock.observe(message.lamport);
+                    let span = span!(Level::INFO, "transaction", role = "participant", txid = %message.txid, senderid = %message.senderid);
+                    let _entered = span.enter();
+                    // Rejected inputs leave no trace in the log
                     match message.mtype {
                         MessageType::CoordinatorPropose => {
-                            let mut mes = message.clone();
-                            if self.perform_operation(Some(message.clone())){
-                                mes.mtype = MessageType::ParticipantVoteCommit;
-                            } else {
-                                mes.mtype = MessageType::ParticipantVoteAbort;
+                            event!(Level::INFO, phase = "propose", participant = %self.id_str, lamport = received, "received proposal");
+                            let op_start = Instant::now();
+                            if self.step(&message, ParticipantInput::Propose) {
+                                // Logged before the vote so that LSN order follows Lamport order
+                                self.log.append(message.mtype, message.txid.clone(), message.senderid.clone(), message.opid, received);
+                                let vote_commit = self.perform_operation(Some(message.clone()));
+                                self.tracer.span("perform_operation", &message.txid, op_start, Instant::now());
+                                self.step(&message, ParticipantInput::Vote(vote_commit));
+                                if self.txn_state(&message.txid) == ParticipantState::VotedCommit {
+                                    self.step(&message, ParticipantInput::VoteLogged);
+                                }
+                                self.stats.record("operation", &message.txid, op_start.elapsed());
                             }
-                            self.send(mes);
-                            // self.tx.send(mes).expect("Failed to send participant vote");
                         },
                         MessageType::CoordinatorCommit => {
+                            event!(Level::INFO, phase = "decision", participant = %self.id_str, decision = "commit", lamport = received, "received decision");
+                            if self.step(&message, ParticipantInput::Decision(true)) {
+                                self.log.append(message.mtype, message.txid.clone(), message.senderid.clone(), message.opid, received);
+                            }
                         },
                         MessageType::CoordinatorAbort => {
+                            event!(Level::INFO, phase = "decision", participant = %self.id_str, decision = "abort", lamport = received, "received decision");
+                            if self.step(&message, ParticipantInput::Decision(false)) {
+                                self.log.append(message.mtype, message.txid.clone(), message.senderid.clone(), message.opid, received);
+                            }
                         },
                         MessageType::CoordinatorExit =>{
                             break;
                         } 
                         _ => {
                             // Handle all other message types
-                            let mess = format!("{:?}", message);
-                            println!("{}", mess);
+                            event!(Level::WARN, participant = %self.id_str, unexpected = ?message, "unexpected message");
+                            self.log.append(message.mtype, message.txid.clone(), message.senderid.clone(), message.opid, received);
                         }
                     }
-                    self.log.append(message.mtype.clone(), message.txid.clone(), message.senderid.clone(), message.opid);
                     start = Instant::now();
+                    self.publish_metrics(false);
                 },
                 Err(e) => {
                     // println!("Error:{:?}",e);
@@ -254,6 +480,7 @@ impl Participant {
             }
         // self.wait_for_exit_signal();
         }
+        self.publish_metrics(true);
         self.report_status();
     }
 }
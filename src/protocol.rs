@@ -0,0 +1,321 @@
+//!
+//! protocol.rs
+//! The 2PC protocol of one transaction as pure state-transition functions
+//! over CoordinatorState and ParticipantState. A step takes the current
+//! state and an input (a message or a local event) and returns the next
+//! state plus the actions the caller has to carry out, or an
+//! IllegalTransition if the input makes no sense in that state. The
+//! functions do no I/O, so the roles and the model checker (explore.rs)
+//! share them.
+//!
+use std::fmt;
+
+use coordinator::CoordinatorState;
+use participant::ParticipantState;
+
+///
+/// IllegalTransition
+/// An input that is not allowed in the state it arrived in
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IllegalTransition<S, I> {
+    pub state: S,
+    pub input: I,
+}
+
+impl<S: fmt::Debug, I: fmt::Debug> fmt::Display for IllegalTransition<S, I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "illegal transition: {:?} in state {:?}", self.input, self.state)
+    }
+}
+
+/// Target of rejected inputs in transition_key()
+pub const REJECTED: &str = "Rejected";
+
+///
+/// transition_key(from, input, to)
+/// Name under which the roles count a transition in their stats report,
+/// e.g. "ReceivedP1 --Vote(commit)--> VotedCommit". Rejected inputs are
+/// counted with REJECTED as target.
+///
+pub fn transition_key(from: &str, input: &str, to: &str) -> String {
+    format!("{} --{}--> {}", from, input, to)
+}
+
+///
+/// outcome(commit)
+/// "commit" or "abort"
+///
+fn outcome(commit: bool) -> &'static str {
+    if commit { "commit" } else { "abort" }
+}
+
+///
+/// CoordinatorInput
+/// What can happen to the coordinator's side of a transaction
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CoordinatorInput {
+    Request,                // A client asked for the transaction
+    ProposalsSent,          // The proposal went out to every participant
+    Vote(usize, bool),      // Participant i voted commit (true) or abort
+    Timeout,                // Gave up waiting for the missing votes
+    DecisionSent,           // The decision was logged and sent
+}
+
+impl fmt::Display for CoordinatorInput {
+    /// The kind of input, without the participant a vote came from
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoordinatorInput::Vote(_, commit) => write!(f, "Vote({})", outcome(*commit)),
+            input => write!(f, "{:?}", input),
+        }
+    }
+}
+
+///
+/// CoordinatorAction
+/// What the coordinator has to do after a step
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CoordinatorAction {
+    SendProposals,          // Send the proposal to every participant
+    Decide(bool),           // Log, force and send commit (true) or abort
+}
+
+///
+/// CoordinatorTxn
+/// The coordinator's state for one transaction: where it is in the state
+/// machine, the votes received so far by participant, and the decision once
+/// taken
+///
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CoordinatorTxn {
+    pub state: CoordinatorState,
+    pub votes: Vec<Option<bool>>,
+    pub decision: Option<bool>,
+}
+
+impl CoordinatorTxn {
+
+    ///
+    /// new(num_participants)
+    /// A transaction nobody asked for yet
+    ///
+    pub fn new(num_participants: usize) -> CoordinatorTxn {
+        CoordinatorTxn {
+            state: CoordinatorState::Quiescent,
+            votes: vec![None; num_participants],
+            decision: None,
+        }
+    }
+
+    ///
+    /// step(input)
+    /// Commit needs a commit vote from every participant; the first abort
+    /// vote or a timeout decides abort. Votes arriving after the decision
+    /// are stragglers and change nothing, but a participant may not vote
+    /// twice with different votes.
+    ///
+    pub fn step(&self, input: CoordinatorInput) -> Result<(CoordinatorTxn, Vec<CoordinatorAction>), IllegalTransition<CoordinatorState, CoordinatorInput>> {
+        use coordinator::CoordinatorState::*;
+        use self::CoordinatorInput::*;
+
+        let illegal = IllegalTransition { state: self.state, input };
+        let mut next = self.clone();
+        let mut actions = Vec::new();
+        match (self.state, input) {
+            (Quiescent, Request) => {
+                next.state = ReceivedRequest;
+                actions.push(CoordinatorAction::SendProposals);
+            },
+            (ReceivedRequest, ProposalsSent) => next.state = ProposalSent,
+            (state, Vote(i, commit)) if state != Quiescent && state != ReceivedRequest => {
+                match self.votes.get(i) {
+                    None => return Err(illegal),
+                    Some(Some(previous)) if *previous != commit => return Err(illegal),
+                    _ => next.votes[i] = Some(commit),
+                }
+                if self.state == ProposalSent {
+                    if !commit {
+                        next.state = ReceivedVotesAbort;
+                        next.decision = Some(false);
+                        actions.push(CoordinatorAction::Decide(false));
+                    } else if next.votes.iter().all(|v| *v == Some(true)) {
+                        next.state = ReceivedVotesCommit;
+                        next.decision = Some(true);
+                        actions.push(CoordinatorAction::Decide(true));
+                    }
+                }
+            },
+            (ProposalSent, Timeout) => {
+                next.state = ReceivedVotesAbort;
+                next.decision = Some(false);
+                actions.push(CoordinatorAction::Decide(false));
+            },
+            (ReceivedVotesCommit, DecisionSent) | (ReceivedVotesAbort, DecisionSent) => next.state = SentGlobalDecision,
+            _ => return Err(illegal),
+        }
+        Ok((next, actions))
+    }
+}
+
+///
+/// ParticipantInput
+/// What can happen to a participant's side of a transaction
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ParticipantInput {
+    Propose,                // The coordinator's proposal arrived
+    Vote(bool),             // The operation succeeded (true) or failed
+    VoteLogged,             // The commit vote is on stable storage
+    Decision(bool),         // The coordinator's commit (true) or abort arrived
+}
+
+impl fmt::Display for ParticipantInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParticipantInput::Vote(commit) => write!(f, "Vote({})", outcome(*commit)),
+            ParticipantInput::Decision(commit) => write!(f, "Decision({})", outcome(*commit)),
+            input => write!(f, "{:?}", input),
+        }
+    }
+}
+
+///
+/// ParticipantAction
+/// What a participant has to do after a step
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ParticipantAction {
+    LogVote(bool),          // Log and force the vote
+    SendVote(bool),         // Send the vote to the coordinator
+    Apply(bool),            // Make the transaction's effects durable (true) or undo them
+}
+
+impl ParticipantState {
+
+    ///
+    /// step(input)
+    /// A commit vote is logged (VotedCommit) and only then sent
+    /// (AwaitingGlobalDecision), after which the participant is uncertain
+    /// until the decision arrives. An abort vote is unilateral: the
+    /// participant knows the outcome, so a commit decision after it is
+    /// illegal. Abort may arrive at any time (the coordinator timed out),
+    /// commit only once the commit vote is out. After the decision the
+    /// participant is Quiescent again.
+    ///
+    pub fn step(self, input: ParticipantInput) -> Result<(ParticipantState, Vec<ParticipantAction>), IllegalTransition<ParticipantState, ParticipantInput>> {
+        use participant::ParticipantState::*;
+        use self::ParticipantAction::*;
+        use self::ParticipantInput::*;
+
+        match (self, input) {
+            (Quiescent, Propose) => Ok((ReceivedP1, vec![])),
+            (ReceivedP1, Vote(true)) => Ok((VotedCommit, vec![LogVote(true)])),
+            (ReceivedP1, Vote(false)) => Ok((VotedAbort, vec![LogVote(false), SendVote(false)])),
+            (VotedCommit, VoteLogged) => Ok((AwaitingGlobalDecision, vec![SendVote(true)])),
+            (AwaitingGlobalDecision, Decision(commit)) => Ok((Quiescent, vec![Apply(commit)])),
+            (_, Decision(false)) => Ok((Quiescent, vec![Apply(false)])),
+            _ => Err(IllegalTransition { state: self, input }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use coordinator::CoordinatorState;
+    use participant::ParticipantState;
+    use super::*;
+
+    /// Feeds inputs to a fresh two-participant transaction, failing on the
+    /// first illegal one, and returns the final state and every action
+    fn run_coordinator(inputs: &[CoordinatorInput]) -> (CoordinatorTxn, Vec<CoordinatorAction>) {
+        let mut txn = CoordinatorTxn::new(2);
+        let mut all = Vec::new();
+        for input in inputs {
+            let (next, actions) = txn.step(*input).unwrap_or_else(|e| panic!("{}", e));
+            txn = next;
+            all.extend(actions);
+        }
+        (txn, all)
+    }
+
+    #[test]
+    fn coordinator_transition_table() {
+        use self::CoordinatorInput::*;
+        use coordinator::CoordinatorState::*;
+        // (state reached by these inputs, next input, state after it, actions)
+        let table: Vec<(&[CoordinatorInput], CoordinatorInput, CoordinatorState, Vec<CoordinatorAction>)> = vec![
+            (&[], Request, ReceivedRequest, vec![CoordinatorAction::SendProposals]),
+            (&[Request], ProposalsSent, ProposalSent, vec![]),
+            (&[Request, ProposalsSent], Vote(0, true), ProposalSent, vec![]),
+            (&[Request, ProposalsSent, Vote(0, true)], Vote(1, true), ReceivedVotesCommit, vec![CoordinatorAction::Decide(true)]),
+            (&[Request, ProposalsSent], Vote(1, false), ReceivedVotesAbort, vec![CoordinatorAction::Decide(false)]),
+            (&[Request, ProposalsSent, Vote(0, true)], Timeout, ReceivedVotesAbort, vec![CoordinatorAction::Decide(false)]),
+            (&[Request, ProposalsSent, Timeout], Vote(0, true), ReceivedVotesAbort, vec![]),
+            (&[Request, ProposalsSent, Timeout], DecisionSent, SentGlobalDecision, vec![]),
+            (&[Request, ProposalsSent, Vote(0, true), Vote(1, true), DecisionSent], Vote(1, true), SentGlobalDecision, vec![]),
+        ];
+        for (history, input, state, actions) in table {
+            let (txn, _) = run_coordinator(history);
+            let (next, taken) = txn.step(input).unwrap_or_else(|e| panic!("after {:?}: {}", history, e));
+            assert_eq!((next.state, taken), (state, actions), "{:?} after {:?}", input, history);
+        }
+        let (committed, _) = run_coordinator(&[Request, ProposalsSent, Vote(0, true), Vote(1, true)]);
+        assert_eq!(committed.decision, Some(true));
+    }
+
+    #[test]
+    fn coordinator_rejects_illegal_inputs() {
+        use self::CoordinatorInput::*;
+        let illegal: Vec<(&[CoordinatorInput], CoordinatorInput)> = vec![
+            (&[], Vote(0, true)),
+            (&[], Timeout),
+            (&[Request], Request),
+            (&[Request, ProposalsSent], Vote(2, true)),
+            (&[Request, ProposalsSent, Vote(0, true)], Vote(0, false)),
+            (&[Request, ProposalsSent], DecisionSent),
+            (&[Request, ProposalsSent, Timeout], Timeout),
+        ];
+        for (history, input) in illegal {
+            let (txn, _) = run_coordinator(history);
+            let err = txn.step(input).expect_err(&format!("{:?} after {:?} was accepted", input, history));
+            assert_eq!((err.state, err.input), (txn.state, input));
+        }
+    }
+
+    #[test]
+    fn participant_transition_table() {
+        use self::ParticipantAction::*;
+        use self::ParticipantInput::*;
+        use participant::ParticipantState::*;
+        let table = vec![
+            (Quiescent, Propose, Some((ReceivedP1, vec![]))),
+            (ReceivedP1, Vote(true), Some((VotedCommit, vec![LogVote(true)]))),
+            (ReceivedP1, Vote(false), Some((VotedAbort, vec![LogVote(false), SendVote(false)]))),
+            (VotedCommit, VoteLogged, Some((AwaitingGlobalDecision, vec![SendVote(true)]))),
+            (AwaitingGlobalDecision, Decision(true), Some((Quiescent, vec![Apply(true)]))),
+            (AwaitingGlobalDecision, Decision(false), Some((Quiescent, vec![Apply(false)]))),
+            (VotedAbort, Decision(false), Some((Quiescent, vec![Apply(false)]))),
+            (ReceivedP1, Decision(false), Some((Quiescent, vec![Apply(false)]))),
+            (VotedAbort, Decision(true), None),
+            (VotedCommit, Decision(true), None),
+            (Quiescent, Decision(true), None),
+            (ReceivedP1, Propose, None),
+            (Quiescent, VoteLogged, None),
+        ];
+        for (state, input, expected) in table {
+            let got = state.step(input).ok();
+            assert_eq!(got, expected, "{:?} in state {:?}", input, state);
+        }
+    }
+
+    #[test]
+    fn transition_keys_name_inputs_without_voters() {
+        assert_eq!(transition_key("ProposalSent", &CoordinatorInput::Vote(3, false).to_string(), REJECTED),
+                   "ProposalSent --Vote(abort)--> Rejected");
+        assert_eq!(ParticipantInput::Decision(true).to_string(), "Decision(commit)");
+        assert_eq!(ParticipantState::Quiescent.step(ParticipantInput::Propose).unwrap().0, ParticipantState::ReceivedP1);
+    }
+}
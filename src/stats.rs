@@ -0,0 +1,340 @@
+//!
+//! stats.rs
+//! Per-transaction latency recording for the 2PC roles. Each role records one
+//! sample per txid for every phase it measures, and at exit reports its
+//! counters, latency histograms (p50/p90/p99/max) and throughput, both human
+//! readable and as a JSON file under log_path.
+//!
+extern crate serde;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::time::{Duration, Instant};
+
+/// Upper bounds (in microseconds) of the histogram buckets; anything slower
+/// falls into a final overflow bucket
+const BUCKET_BOUNDS_US: [u64; 16] = [
+    50, 100, 250, 500,
+    1_000, 2_500, 5_000, 10_000,
+    25_000, 50_000, 100_000, 250_000,
+    500_000, 1_000_000, 2_500_000, 5_000_000,
+];
+
+///
+/// Bucket
+/// Number of samples at or below `le_us` (and above the previous bound)
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Bucket {
+    pub le_us: Option<u64>,   // Upper bound, None for the overflow bucket
+    pub count: u64,
+}
+
+///
+/// LatencySummary
+/// Histogram of one measured phase
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min_us: u64,
+    pub mean_us: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+    #[serde(default)]
+    pub sum_us: u64,
+    pub buckets: Vec<Bucket>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub samples: BTreeMap<String, u64>,   // txid -> latency in microseconds
+}
+
+///
+/// StatsReport
+/// Everything one role reports about itself at exit
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct StatsReport {
+    pub id: String,
+    pub counters: BTreeMap<String, u64>,          // e.g. "committed" -> 12
+    pub elapsed_ms: u64,
+    pub completed: u64,
+    pub throughput: f64,                          // completed transactions per second
+    pub latencies: BTreeMap<String, LatencySummary>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub state: String,                            // 2PC roles: state of the current transaction
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub states: BTreeMap<String, u64>,            // 2PC roles: transactions per state
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub transitions: BTreeMap<String, u64>,       // 2PC roles: times each transition was taken (see protocol::transition_key)
+}
+
+///
+/// LatencyStats
+/// Collects latency samples per phase and txid for one role
+///
+#[derive(Debug)]
+pub struct LatencyStats {
+    started: Instant,
+    last_sample: Option<Instant>,
+    phases: BTreeMap<String, Vec<(String, Duration)>>,
+}
+
+impl LatencyStats {
+
+    ///
+    /// new()
+    /// Creates an empty recorder whose throughput clock starts now
+    ///
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> LatencyStats {
+        LatencyStats {
+            started: Instant::now(),
+            last_sample: None,
+            phases: BTreeMap::new(),
+        }
+    }
+
+    ///
+    /// restart_clock()
+    /// Restarts the clock used for throughput, e.g. once setup is done
+    ///
+    pub fn restart_clock(&mut self) {
+        self.started = Instant::now();
+        self.last_sample = None;
+    }
+
+    ///
+    /// elapsed()
+    /// Time from the start of the throughput clock to the last recorded
+    /// sample, so that idle time while shutting down does not count
+    ///
+    pub fn elapsed(&self) -> Duration {
+        match self.last_sample {
+            Some(t) if t > self.started => t - self.started,
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    ///
+    /// record(phase, txid, latency)
+    /// Records the latency of one phase of the transaction txid
+    ///
+    pub fn record(&mut self, phase: &str, txid: &str, latency: Duration) {
+        self.phases.entry(phase.to_string())
+            .or_default()
+            .push((txid.to_string(), latency));
+        self.last_sample = Some(Instant::now());
+    }
+
+    ///
+    /// report(id, counters, completed)
+    /// Summarizes all recorded phases together with the role's counters.
+    /// `completed` is the number of transactions this role finished, used
+    /// for throughput.
+    ///
+    pub fn report(&self, id: &str, counters: &[(&str, u32)], completed: u64) -> StatsReport {
+        let elapsed = self.elapsed();
+        let secs = elapsed.as_secs_f64();
+        StatsReport {
+            id: id.to_string(),
+            counters: counters.iter()
+                .map(|(name, value)| (name.to_string(), *value as u64))
+                .collect(),
+            elapsed_ms: elapsed.as_millis() as u64,
+            completed,
+            throughput: if secs > 0.0 { completed as f64 / secs } else { 0.0 },
+            latencies: self.phases.iter()
+                .map(|(phase, samples)| (phase.clone(), summarize(samples)))
+                .collect(),
+            state: String::new(),
+            states: BTreeMap::new(),
+            transitions: BTreeMap::new(),
+        }
+    }
+}
+
+///
+/// summarize(samples)
+/// Computes percentiles and histogram buckets for a list of samples
+///
+fn summarize(samples: &[(String, Duration)]) -> LatencySummary {
+    summarize_us(samples.iter()
+        .map(|(txid, d)| (txid.clone(), d.as_micros() as u64))
+        .collect())
+}
+
+///
+/// summarize_us(samples)
+/// Computes percentiles and histogram buckets for (txid, microseconds) pairs
+///
+fn summarize_us(samples: BTreeMap<String, u64>) -> LatencySummary {
+    if samples.is_empty() {
+        return LatencySummary::default();
+    }
+    let mut us: Vec<u64> = samples.values().cloned().collect();
+    us.sort_unstable();
+
+    let mut buckets: Vec<Bucket> = BUCKET_BOUNDS_US.iter()
+        .map(|b| Bucket { le_us: Some(*b), count: 0 })
+        .collect();
+    buckets.push(Bucket { le_us: None, count: 0 });
+    for v in &us {
+        let i = BUCKET_BOUNDS_US.iter().position(|b| v <= b).unwrap_or(BUCKET_BOUNDS_US.len());
+        buckets[i].count += 1;
+    }
+
+    let sum_us = us.iter().sum::<u64>();
+    LatencySummary {
+        count: us.len() as u64,
+        min_us: us[0],
+        mean_us: sum_us / us.len() as u64,
+        p50_us: percentile(&us, 50.0),
+        p90_us: percentile(&us, 90.0),
+        p99_us: percentile(&us, 99.0),
+        max_us: us[us.len() - 1],
+        sum_us,
+        buckets,
+        samples,
+    }
+}
+
+///
+/// merge(reports, phase)
+/// Combines the samples of one phase across several reports (e.g. all
+/// clients of a run) into a single histogram. Reports must still carry
+/// their per-txid samples.
+///
+pub fn merge(reports: &[StatsReport], phase: &str) -> LatencySummary {
+    let mut samples = BTreeMap::new();
+    for r in reports {
+        if let Some(l) = r.latencies.get(phase) {
+            samples.extend(l.samples.iter().map(|(txid, us)| (txid.clone(), *us)));
+        }
+    }
+    summarize_us(samples)
+}
+
+///
+/// percentile(sorted, p)
+/// Nearest-rank percentile of a sorted, non-empty slice
+///
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl StatsReport {
+
+    ///
+    /// print()
+    /// Prints throughput and one latency line per phase to stdout
+    ///
+    pub fn print(&self) {
+        println!("{:16}:\tCompleted: {:6}\tElapsed: {:8.3}s\tThroughput: {:10.2} tx/s",
+                 self.id, self.completed, self.elapsed_ms as f64 / 1000.0, self.throughput);
+        for (phase, l) in &self.latencies {
+            println!("{:16}:\t{:16} n={:6}\tp50={:9.3}ms\tp90={:9.3}ms\tp99={:9.3}ms\tmax={:9.3}ms",
+                     self.id, phase, l.count,
+                     l.p50_us as f64 / 1000.0,
+                     l.p90_us as f64 / 1000.0,
+                     l.p99_us as f64 / 1000.0,
+                     l.max_us as f64 / 1000.0);
+        }
+    }
+
+    ///
+    /// write_json(path)
+    /// Writes the report as pretty-printed JSON to the given file
+    ///
+    pub fn write_json(&self, path: &str) -> io::Result<()> {
+        let f = File::create(path)?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+
+    ///
+    /// from_file(path)
+    /// Reads a report previously saved with write_json
+    ///
+    pub fn from_file(path: &str) -> io::Result<StatsReport> {
+        let f = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(f))?)
+    }
+
+    ///
+    /// without_samples()
+    /// Copy of the report without the per-txid samples, for aggregation
+    ///
+    pub fn without_samples(&self) -> StatsReport {
+        let mut r = self.clone();
+        for l in r.latencies.values_mut() {
+            l.samples.clear();
+        }
+        r
+    }
+}
+
+///
+/// report_path(log_path, id)
+/// Location of the stats report of the role `id` in `log_path`
+///
+pub fn report_path(log_path: &str, id: &str) -> String {
+    format!("{}//{}.stats.json", log_path, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples tx_1..tx_n with latencies 1..n microseconds times `scale`
+    fn samples(n: u64, scale: u64) -> Vec<(String, Duration)> {
+        (1..=n).map(|i| (format!("tx_{}", i), Duration::from_micros(i * scale))).collect()
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let sorted: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&sorted, 50.0), 50);
+        assert_eq!(percentile(&sorted, 90.0), 90);
+        assert_eq!(percentile(&sorted, 99.0), 99);
+        assert_eq!(percentile(&sorted, 0.0), 1);
+        assert_eq!(percentile(&[7], 99.0), 7);
+        assert_eq!(percentile(&[1, 2, 3], 50.0), 2);
+    }
+
+    #[test]
+    fn summary_fills_histogram_buckets() {
+        let l = summarize(&samples(10, 100));
+        assert_eq!((l.count, l.min_us, l.mean_us, l.max_us, l.sum_us), (10, 100, 550, 1000, 5500));
+        assert_eq!((l.p50_us, l.p90_us, l.p99_us), (500, 900, 1000));
+        let counts: Vec<(Option<u64>, u64)> = l.buckets.iter()
+            .filter(|b| b.count > 0)
+            .map(|b| (b.le_us, b.count))
+            .collect();
+        assert_eq!(counts, vec![(Some(100), 1), (Some(250), 1), (Some(500), 3), (Some(1_000), 5)]);
+        assert_eq!(l.buckets.len(), BUCKET_BOUNDS_US.len() + 1);
+        assert_eq!(l.samples.get("tx_3"), Some(&300));
+
+        let slow = summarize(&samples(1, 10_000_000));
+        assert_eq!(slow.buckets.last().map(|b| (b.le_us, b.count)), Some((None, 1)));
+        assert_eq!(summarize(&[]).count, 0);
+    }
+
+    #[test]
+    fn merge_combines_samples_of_one_phase() {
+        let report = |id: &str, from: u64| StatsReport {
+            id: id.to_string(),
+            latencies: vec![("end_to_end".to_string(), summarize_us((from..from + 5).map(|i| (format!("tx_{}", i), i)).collect()))]
+                .into_iter().collect(),
+            ..StatsReport::default()
+        };
+        let merged = merge(&[report("client_0", 1), report("client_1", 6)], "end_to_end");
+        assert_eq!((merged.count, merged.min_us, merged.max_us, merged.p50_us), (10, 1, 10, 5));
+        assert_eq!(merge(&[report("client_0", 1)], "missing").count, 0);
+    }
+}
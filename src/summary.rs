@@ -0,0 +1,138 @@
+//!
+//! summary.rs
+//! Machine-readable summary of a whole 2PC run. After every child process has
+//! exited, the run mode collects the coordinator's report, the reports the
+//! clients and participants left in log_path and their exit statuses, and
+//! writes them together with the options used to summary.json.
+//!
+extern crate serde;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::process::ExitStatus;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use oplog::LogConfig;
+use stats;
+use stats::StatsReport;
+use tpcoptions::TPCOptions;
+
+///
+/// ChildSummary
+/// Exit status and final report of one client or participant process
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ChildSummary {
+    pub id: String,
+    pub success: bool,
+    pub exit_code: Option<i32>,     // None if the child was killed by a signal
+    pub exit_status: String,        // Human readable status, e.g. "signal: 9"
+    pub stats: Option<StatsReport>, // None if the child did not leave a report
+}
+
+impl ChildSummary {
+
+    ///
+    /// new(id, status, log_path)
+    /// Summarizes a child that exited with `status`, picking up the stats
+    /// report it wrote to log_path (if any)
+    ///
+    pub fn new(id: &str, status: &ExitStatus, log_path: &str) -> ChildSummary {
+        let path = stats::report_path(log_path, id);
+        let stats = match StatsReport::from_file(&path) {
+            Ok(r) => Some(r.without_samples()),
+            Err(e) => {
+                warn!("No stats report for {} at \"{}\": {:?}", id, path, e);
+                None
+            }
+        };
+        ChildSummary {
+            id: id.to_string(),
+            success: status.success(),
+            exit_code: status.code(),
+            exit_status: status.to_string(),
+            stats,
+        }
+    }
+}
+
+///
+/// RunSummary
+/// Everything an experiment script needs to know about one run
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct RunSummary {
+    pub options: TPCOptions,
+    pub log: LogConfig,                             // log format and durability in effect
+    pub started_unix_ms: u64,
+    pub duration_ms: u64,
+    pub coordinator: StatsReport,
+    pub participants: Vec<ChildSummary>,
+    pub clients: Vec<ChildSummary>,
+    pub participant_totals: BTreeMap<String, u64>,  // counters summed over participants
+    pub client_totals: BTreeMap<String, u64>,       // counters summed over clients
+}
+
+impl RunSummary {
+
+    ///
+    /// new(options, started, duration, coordinator, participants, clients)
+    /// Builds the summary and sums up the per-role counters
+    ///
+    pub fn new(
+        options: &TPCOptions,
+        started: SystemTime,
+        duration: Duration,
+        coordinator: &StatsReport,
+        participants: Vec<ChildSummary>,
+        clients: Vec<ChildSummary>) -> RunSummary {
+
+        RunSummary {
+            options: options.clone(),
+            log: LogConfig::from_options(options),
+            started_unix_ms: started.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            duration_ms: duration.as_millis() as u64,
+            coordinator: coordinator.without_samples(),
+            participant_totals: totals(&participants),
+            client_totals: totals(&clients),
+            participants,
+            clients,
+        }
+    }
+
+    ///
+    /// write_json(path)
+    /// Writes the summary as pretty-printed JSON to the given file
+    ///
+    pub fn write_json(&self, path: &str) -> io::Result<()> {
+        let f = File::create(path)?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+}
+
+///
+/// totals(children)
+/// Sums every counter over all children that left a report
+///
+fn totals(children: &[ChildSummary]) -> BTreeMap<String, u64> {
+    let mut sum = BTreeMap::new();
+    for report in children.iter().filter_map(|c| c.stats.as_ref()) {
+        for (name, value) in &report.counters {
+            *sum.entry(name.clone()).or_insert(0) += *value;
+        }
+    }
+    sum
+}
+
+///
+/// summary_path(log_path)
+/// Location of the run summary in `log_path`
+///
+pub fn summary_path(log_path: &str) -> String {
+    format!("{}//{}", log_path, "summary.json")
+}
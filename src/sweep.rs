@@ -0,0 +1,298 @@
+//!
+//! sweep.rs
+//! Parameter sweeps over 2PC runs. Expands a sweep spec such as
+//! "S=1.0,0.95,0.9;s=1.0,0.9;p=3,5,9" into the cross product of its axes,
+//! executes every configuration (optionally several times) as a separate
+//! "run" process with its own log directory, checks the logs with a "check"
+//! process, and appends one CSV row per run to sweep.csv in log_path.
+//!
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use stats;
+use stats::StatsReport;
+use summary;
+use summary::RunSummary;
+use tpcoptions::TPCOptions;
+use workload;
+
+/// Column names of sweep.csv; every row written by `csv_row` follows this order
+const CSV_HEADER: &str = "run,repeat,send_success_probability,operation_success_probability,\
+num_participants,num_clients,num_requests,arrival,rate,think_time,\
+exit_ok,commit_rate,abort_rate,unknown_rate,throughput,\
+p50_ms,p90_ms,p99_ms,max_ms,checker,log_path";
+
+///
+/// SweepAxis
+/// One option of the sweep and the values it takes
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct SweepAxis {
+    pub key: String,
+    pub values: Vec<String>,
+}
+
+///
+/// parse_spec(spec)
+/// Parses "key=v1,v2;key=v3" into axes. Keys are the short CLI flags (S, s,
+/// p, c, r) or long option names; values may be wrapped in braces, e.g.
+/// "S={1.0,0.9}". Values are validated when the axes are expanded.
+///
+pub fn parse_spec(spec: &str) -> Result<Vec<SweepAxis>, String> {
+    let mut axes = Vec::new();
+    for part in spec.split(';').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let eq = part.find('=').ok_or(format!("Sweep axis \"{}\" is not of the form key=values", part))?;
+        let key = part[..eq].trim().to_string();
+        let values: Vec<String> = part[eq + 1..].trim()
+            .trim_start_matches('{')
+            .trim_end_matches('}')
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if values.is_empty() {
+            return Err(format!("Sweep axis \"{}\" has no values", key));
+        }
+        if axes.iter().any(|a: &SweepAxis| a.key == key) {
+            return Err(format!("Sweep axis \"{}\" given twice", key));
+        }
+        axes.push(SweepAxis { key, values });
+    }
+    if axes.is_empty() {
+        return Err(format!("Empty sweep spec \"{}\"", spec));
+    }
+    Ok(axes)
+}
+
+///
+/// apply(opts, key, value)
+/// Sets the option named by a sweep key
+///
+fn apply(opts: &mut TPCOptions, key: &str, value: &str) -> Result<(), String> {
+    let bad = |e: &dyn std::fmt::Display| format!("Invalid value \"{}\" for sweep axis \"{}\": {}", value, key, e);
+    match key {
+        "S" | "send_success_probability" => opts.send_success_probability = value.parse().map_err(|e| bad(&e))?,
+        "s" | "operation_success_probability" => opts.operation_success_probability = value.parse().map_err(|e| bad(&e))?,
+        "p" | "num_participants" => opts.num_participants = value.parse().map_err(|e| bad(&e))?,
+        "c" | "num_clients" => opts.num_clients = value.parse().map_err(|e| bad(&e))?,
+        "r" | "num_requests" => opts.num_requests = value.parse().map_err(|e| bad(&e))?,
+        "rate" => opts.rate = workload::parse_rate(value).map_err(|e| bad(&e))?,
+        "think_time" => opts.think_time = value.parse().map_err(|e| bad(&e))?,
+        "arrival" => match value {
+            "constant" | "poisson" => opts.arrival = value.to_string(),
+            _ => return Err(bad(&"expected \"constant\" or \"poisson\"")),
+        },
+        _ => return Err(format!("Unknown sweep axis \"{}\"", key)),
+    }
+    Ok(())
+}
+
+///
+/// configurations(base, axes)
+/// Cross product of all axes applied on top of the base options. Fails on
+/// the first unknown key or invalid value, before anything has been run.
+///
+pub fn configurations(base: &TPCOptions, axes: &[SweepAxis]) -> Result<Vec<TPCOptions>, String> {
+    let mut configs = vec![base.clone()];
+    for axis in axes {
+        let mut next = Vec::new();
+        for c in &configs {
+            for v in &axis.values {
+                let mut o = c.clone();
+                apply(&mut o, &axis.key, v)?;
+                next.push(o);
+            }
+        }
+        configs = next;
+    }
+    Ok(configs)
+}
+
+///
+/// RunResult
+/// What one run of a sweep produced
+///
+struct RunResult {
+    exit_ok: bool,
+    summary: Option<RunSummary>,
+    client_e2e: stats::LatencySummary,
+    checker_ok: bool,
+}
+
+///
+/// execute(opts)
+/// Runs one configuration in its own process, then checks its logs
+///
+fn execute(opts: &TPCOptions) -> io::Result<RunResult> {
+    fs::create_dir_all(&opts.log_path)?;
+    let exe = env::current_exe()?;
+
+    let mut run_opts = opts.clone();
+    run_opts.mode = "run".to_string();
+    let run_out = File::create(format!("{}//{}", opts.log_path, "run.out"))?;
+    let status = Command::new(&exe)
+        .args(run_opts.as_vec())
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(run_out.try_clone()?))
+        .stderr(Stdio::from(run_out))
+        .status()?;
+
+    let summary_path = summary::summary_path(&opts.log_path);
+    let run_summary: Option<RunSummary> = File::open(&summary_path).ok()
+        .and_then(|f| serde_json::from_reader(io::BufReader::new(f)).ok());
+
+    let client_reports: Vec<StatsReport> = (0..opts.num_clients)
+        .filter_map(|i| StatsReport::from_file(&stats::report_path(&opts.log_path, &format!("client_{}", i))).ok())
+        .collect();
+
+    let mut check_opts = opts.clone();
+    check_opts.mode = "check".to_string();
+    let check_out = File::create(format!("{}//{}", opts.log_path, "check.out"))?;
+    let checker = Command::new(&exe)
+        .args(check_opts.as_vec())
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(check_out.try_clone()?))
+        .stderr(Stdio::from(check_out))
+        .status()?;
+
+    Ok(RunResult {
+        exit_ok: status.success(),
+        summary: run_summary,
+        client_e2e: stats::merge(&client_reports, "client_e2e"),
+        checker_ok: checker.success(),
+    })
+}
+
+///
+/// csv_row(run, rep, opts, result)
+/// Formats one line of sweep.csv
+///
+fn csv_row(run: usize, rep: u32, opts: &TPCOptions, result: &RunResult) -> String {
+    let issued = (opts.num_clients * opts.num_requests) as f64;
+    let rate = |counter: &str| -> String {
+        match result.summary {
+            Some(ref s) if issued > 0.0 => {
+                let n = s.client_totals.get(counter).cloned().unwrap_or(0);
+                format!("{:.4}", n as f64 / issued)
+            },
+            _ => String::new(),
+        }
+    };
+    let throughput = match result.summary {
+        Some(ref s) => format!("{:.2}", s.coordinator.throughput),
+        None => String::new(),
+    };
+    let l = &result.client_e2e;
+    let ms = |us: u64| format!("{:.3}", us as f64 / 1000.0);
+    format!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            run, rep,
+            opts.send_success_probability, opts.operation_success_probability,
+            opts.num_participants, opts.num_clients, opts.num_requests,
+            opts.arrival, opts.rate, opts.think_time,
+            result.exit_ok,
+            rate("committed"), rate("aborted"), rate("unknown"),
+            throughput,
+            ms(l.p50_us), ms(l.p90_us), ms(l.p99_us), ms(l.max_us),
+            if result.checker_ok { "pass" } else { "fail" },
+            opts.log_path)
+}
+
+///
+/// run_sweep(opts, running)
+/// Executes every configuration of the sweep spec `opts.repeat` times and
+/// writes sweep.csv to opts.log_path. Stops early when Ctrl+C is pressed.
+///
+pub fn run_sweep(opts: &TPCOptions, running: Arc<AtomicBool>) {
+    let (axes, configs) = match parse_spec(&opts.sweep).and_then(|a| configurations(opts, &a).map(|c| (a, c))) {
+        Ok(r) => r,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let total = configs.len() * opts.repeat as usize;
+    info!("Sweep: {} configurations x {} repeats = {} runs", configs.len(), opts.repeat, total);
+
+    let csv_path = format!("{}//{}", opts.log_path, "sweep.csv");
+    let mut csv = File::create(&csv_path).expect("Failed to create sweep.csv");
+    writeln!(csv, "{}", CSV_HEADER).expect("Failed to write sweep.csv");
+
+    let mut failed = 0;
+    for (run, config) in configs.iter().enumerate() {
+        for rep in 0..opts.repeat {
+            if !running.load(Ordering::SeqCst) {
+                println!("Sweep interrupted, results so far are in {}", csv_path);
+                return;
+            }
+            let mut o = config.clone();
+            o.log_path = format!("{}//run_{:04}_{}", opts.log_path, run, rep);
+            let settings: Vec<String> = axes.iter()
+                .map(|a| format!("{}={}", a.key, value_of(&o, &a.key)))
+                .collect();
+            println!("[{}/{}] {} (repeat {})", run * opts.repeat as usize + rep as usize + 1, total, settings.join(" "), rep);
+            match execute(&o) {
+                Ok(result) => {
+                    if !result.exit_ok || !result.checker_ok {
+                        failed += 1;
+                    }
+                    writeln!(csv, "{}", csv_row(run, rep, &o, &result)).expect("Failed to write sweep.csv");
+                    csv.flush().expect("Failed to write sweep.csv");
+                },
+                Err(e) => {
+                    failed += 1;
+                    error!("Run {} repeat {} failed to execute: {:?}", run, rep, e);
+                }
+            }
+        }
+    }
+    println!("Sweep done: {} runs, {} failed or did not check out. Results in {}", total, failed, csv_path);
+}
+
+///
+/// value_of(opts, key)
+/// Current value of the option named by a sweep key, for progress output
+///
+fn value_of(opts: &TPCOptions, key: &str) -> String {
+    match key {
+        "S" | "send_success_probability" => opts.send_success_probability.to_string(),
+        "s" | "operation_success_probability" => opts.operation_success_probability.to_string(),
+        "p" | "num_participants" => opts.num_participants.to_string(),
+        "c" | "num_clients" => opts.num_clients.to_string(),
+        "r" | "num_requests" => opts.num_requests.to_string(),
+        "rate" => opts.rate.to_string(),
+        "think_time" => opts.think_time.to_string(),
+        "arrival" => opts.arrival.clone(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_is_split_into_axes() {
+        let axes = parse_spec("S={1.0, 0.9}; p=2,3 ;rate=10/s").unwrap();
+        let parsed: Vec<(&str, Vec<&str>)> = axes.iter()
+            .map(|a| (a.key.as_str(), a.values.iter().map(|v| v.as_str()).collect()))
+            .collect();
+        assert_eq!(parsed, vec![("S", vec!["1.0", "0.9"]), ("p", vec!["2", "3"]), ("rate", vec!["10/s"])]);
+    }
+
+    #[test]
+    fn malformed_specs_are_rejected() {
+        assert!(parse_spec("").is_err());
+        assert!(parse_spec(" ; ").is_err());
+        assert!(parse_spec("p").is_err());
+        assert!(parse_spec("p={}").is_err());
+        assert!(parse_spec("p=1;p=2").is_err());
+    }
+}
@@ -0,0 +1,242 @@
+//!
+//! timeline.rs
+//! Merged view of a whole run. Loads the coordinator and participant OpLogs
+//! in log_path and arranges their records into one timeline per txid: each
+//! participant's receipt of the proposal, its vote, the coordinator's receipt
+//! of the votes, the decision and each participant's receipt of it. Written
+//! as text and as a Mermaid sequence diagram.
+//!
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+use message::MessageType;
+use oplog::{LogRecord, OpLog};
+use tpcoptions::TPCOptions;
+
+const COORDINATOR: &str = "coordinator";
+
+///
+/// Phase
+/// Step of 2PC an event belongs to. Events of a transaction are ordered by
+/// Lamport time, then by phase, as every phase causally follows the one
+/// before it. For logs without Lamport times the phase alone decides; wall
+/// clock times only order events within a phase: participants log the
+/// proposal after their vote, so its timestamp can precede the vote's.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Propose,            // A participant received the proposal
+    Vote,               // A participant voted
+    VoteReceipt,        // The coordinator received a vote
+    Decision,           // The coordinator logged its decision
+    DecisionReceipt,    // A participant received the decision
+}
+
+///
+/// Event
+/// One step of one transaction, taken from a log record
+///
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub phase: Phase,
+    pub actor: String,      // Whose log the record is from
+    pub mtype: MessageType,
+    pub lamport: u64,
+    pub timestamp_us: u64,
+    pub lsn: u32,
+}
+
+impl Event {
+
+    ///
+    /// from_record(actor, rec)
+    /// The event a log record stands for, None for records that are not
+    /// part of a transaction's timeline
+    ///
+    fn from_record(actor: &str, rec: &LogRecord) -> Option<Event> {
+        let is_coordinator = actor == COORDINATOR;
+        let phase = match rec.message.mtype {
+            MessageType::CoordinatorPropose if !is_coordinator => Phase::Propose,
+            MessageType::ParticipantVoteCommit | MessageType::ParticipantVoteAbort => {
+                if is_coordinator { Phase::VoteReceipt } else { Phase::Vote }
+            },
+            MessageType::CoordinatorCommit | MessageType::CoordinatorAbort => {
+                if is_coordinator { Phase::Decision } else { Phase::DecisionReceipt }
+            },
+            _ => return None,
+        };
+        Some(Event {
+            phase,
+            actor: actor.to_string(),
+            mtype: rec.message.mtype,
+            lamport: rec.message.lamport,
+            timestamp_us: rec.timestamp_us,
+            lsn: rec.lsn,
+        })
+    }
+
+    ///
+    /// describe()
+    /// One line description for the text timeline
+    ///
+    fn describe(&self) -> String {
+        let name = short_name(self.mtype);
+        match self.phase {
+            Phase::Propose => format!("received {} from {}", name, COORDINATOR),
+            Phase::Vote => format!("votes {}", name.trim_start_matches("Vote")),
+            Phase::VoteReceipt => format!("received a vote ({})", name),
+            Phase::Decision => format!("decides {}", name),
+            Phase::DecisionReceipt => format!("received {} from {}", name, COORDINATOR),
+        }
+    }
+}
+
+///
+/// Timeline
+/// Events of every transaction of a run, in order
+///
+#[derive(Clone, Debug, Default)]
+pub struct Timeline {
+    pub actors: Vec<String>,                        // Coordinator first, then participants
+    pub transactions: Vec<(String, Vec<Event>)>,    // Ordered by first event
+}
+
+impl Timeline {
+
+    ///
+    /// load(log_path, num_participants, txid)
+    /// Merges the logs of a run, keeping only transaction `txid` if given
+    ///
+    pub fn load(log_path: &str, num_participants: u32, txid: Option<&str>) -> Result<Timeline, String> {
+        let mut actors = vec![COORDINATOR.to_string()];
+        actors.extend((0..num_participants).map(|pid| format!("participant_{}", pid)));
+
+        let mut by_txid: BTreeMap<String, Vec<Event>> = BTreeMap::new();
+        for actor in &actors {
+            let path = format!("{}//{}.log", log_path, actor);
+            let log = OpLog::read_only(path.clone()).map_err(|e| format!("Failed to load log \"{}\": {}", path, e))?;
+            if log.checkpointed() {
+                warn!("{}: log was checkpointed, transactions decided before the checkpoint are missing from the timeline", path);
+            }
+            let records = match txid {
+                Some(t) => log.by_txid(t),
+                None => log.iter(),
+            };
+            for rec in records {
+                if let Some(event) = Event::from_record(actor, &rec) {
+                    by_txid.entry(rec.message.txid.clone()).or_default().push(event);
+                }
+            }
+        }
+
+        let mut transactions: Vec<(String, Vec<Event>)> = by_txid.into_iter()
+            .map(|(txid, mut events)| {
+                events.sort_by(|a, b| (a.lamport, a.phase, a.timestamp_us, &a.actor).cmp(&(b.lamport, b.phase, b.timestamp_us, &b.actor)));
+                (txid, events)
+            })
+            .collect();
+        transactions.sort_by_key(|(txid, events)| (events.iter().map(|e| e.timestamp_us).min(), txid.clone()));
+        Ok(Timeline { actors, transactions })
+    }
+
+    ///
+    /// write_text(w)
+    /// Events per transaction with times relative to its first event
+    ///
+    pub fn write_text<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let width = self.actors.iter().map(|a| a.len()).max().unwrap_or(0);
+        for (txid, events) in &self.transactions {
+            writeln!(w, "{}", txid)?;
+            let start = events.iter().map(|e| e.timestamp_us).min().unwrap_or(0);
+            for e in events {
+                writeln!(w, "  +{:>9.3}ms  L{:<6}  {:<width$}  {}",
+                         e.timestamp_us.saturating_sub(start) as f64 / 1000.0,
+                         e.lamport,
+                         e.actor,
+                         e.describe(),
+                         width = width)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// write_mermaid(w)
+    /// A Mermaid sequence diagram of all transactions, each introduced by a
+    /// note naming its txid
+    ///
+    pub fn write_mermaid<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "sequenceDiagram")?;
+        for actor in &self.actors {
+            writeln!(w, "    participant {}", actor)?;
+        }
+        let span = format!("{},{}", COORDINATOR, self.actors.last().map_or(COORDINATOR, |a| a.as_str()));
+        for (txid, events) in &self.transactions {
+            writeln!(w, "    Note over {}: {}", span, txid)?;
+            for e in events {
+                let name = short_name(e.mtype);
+                match e.phase {
+                    Phase::Propose | Phase::DecisionReceipt => writeln!(w, "    {}->>{}: {}", COORDINATOR, e.actor, name)?,
+                    Phase::Vote => writeln!(w, "    {}-->>{}: {}", e.actor, COORDINATOR, name)?,
+                    Phase::Decision => writeln!(w, "    Note over {}: decide {}", COORDINATOR, name)?,
+                    // The vote's arrow already ends at the coordinator
+                    Phase::VoteReceipt => {},
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// short_name(mtype)
+/// Message type without the role prefix, e.g. "Commit" or "VoteAbort"
+///
+fn short_name(mtype: MessageType) -> String {
+    let name = format!("{:?}", mtype);
+    name.trim_start_matches("Coordinator").trim_start_matches("Participant").to_string()
+}
+
+///
+/// timeline_path(log_path, extension)
+/// Location of the exported timeline in `log_path`
+///
+pub fn timeline_path(log_path: &str, extension: &str) -> String {
+    format!("{}//timeline.{}", log_path, extension)
+}
+
+///
+/// write_timeline(opts)
+///
+/// Merges the logs in opts.log_path (only opts.txid if given), prints the
+/// text timeline and writes it to timeline.txt, and the sequence diagram to
+/// timeline.mmd. Returns false if a log could not be loaded or written.
+///
+pub fn write_timeline(opts: &TPCOptions) -> bool {
+    let txid = if opts.txid.is_empty() { None } else { Some(opts.txid.as_str()) };
+    let timeline = match Timeline::load(&opts.log_path, opts.num_participants, txid) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+
+    let text_path = timeline_path(&opts.log_path, "txt");
+    let mermaid_path = timeline_path(&opts.log_path, "mmd");
+    let written = timeline.write_text(&mut io::stdout())
+        .and_then(|_| timeline.write_text(&mut File::create(&text_path)?))
+        .and_then(|_| timeline.write_mermaid(&mut File::create(&mermaid_path)?));
+    match written {
+        Ok(()) => {
+            println!("{} transactions, written to {} and {}", timeline.transactions.len(), text_path, mermaid_path);
+            true
+        },
+        Err(e) => {
+            error!("Failed to write timeline: {}", e);
+            false
+        }
+    }
+}
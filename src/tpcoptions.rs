@@ -10,10 +10,20 @@
 extern crate log;
 extern crate stderrlog;
 extern crate clap;
+extern crate serde;
 use clap::{Arg, App};
+use std::net::SocketAddr;
 
 extern crate ctrlc;
-#[derive(Clone, Debug)]
+
+use explore;
+use inspect;
+use message;
+use monitor;
+use oplog;
+use workload;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct TPCOptions {
     pub send_success_probability: f64,        // Probability that a message send succeeds
     pub operation_success_probability: f64,   // Probability that an operation succeeds
@@ -21,10 +31,32 @@ pub struct TPCOptions {
     pub num_requests: u32,                    // Number of requests issued per client
     pub num_participants: u32,                // Number of participants in 2PC protocol (not including coordinator)
     pub verbosity: usize,                     // Integer verbosity level. experiment with 0 (default) to 5 (fire-hose of output)
-    pub mode: String,                         // One of "run", "client", "particpant", "check"
+    pub log_output: String,                   // stderr log output: "text" or "json"
+    pub mode: String,                         // One of "run", "client", "particpant", "check", "sweep", "verify-log", "inspect", "timeline", "explore", "graph"
     pub log_path: String,                     // Directory for client, participant, and coordinator logs
     pub ipc_path: String,                     // Path to IPC socket for setting up communication with the coordinator
     pub num: u32,                             // Participant / Client number for naming the log files
+    pub arrival: String,                      // Open-loop arrival process: "constant" or "poisson"
+    pub rate: f64,                            // Open-loop request rate per client in requests/s (0 -> closed loop)
+    pub think_time: u64,                      // Closed-loop think time between requests in ms
+    pub workload: String,                     // Operation workload: "none" or "bank"
+    pub accounts: u32,                        // Number of bank accounts, sharded across participants
+    pub initial_balance: i64,                 // Starting balance of every bank account
+    pub log_format: String,                   // OpLog format: "json" or "binary"
+    pub durability: String,                   // OpLog durability: "none", "flush", "fsync" or "group:<records>:<ms>"
+    pub segment_size: String,                 // Rotate OpLog segments at this size, e.g. "1M" (0 -> single file)
+    pub checkpoint_interval: u32,             // Checkpoint OpLogs every N records (0 -> never)
+    pub log_file: String,                     // OpLog for "verify-log"/"inspect" mode (empty -> every log in log_path)
+    pub txid: String,                         // "inspect"/"timeline" filter: transaction id (empty -> any)
+    pub mtype: String,                        // "inspect" filter: MessageType name (empty -> any)
+    pub sender: String,                       // "inspect" filter: sender id (empty -> any)
+    pub lsn: String,                          // "inspect" filter: LSN range "a..b", "a..", "..b" or "a" (empty -> any)
+    pub on_violation: String,                 // What the coordinator's safety monitor does on a violation: "log" or "halt"
+    pub metrics_addr: String,                 // Loopback address of the coordinator's Prometheus endpoint (empty -> off)
+    pub tui: bool,                            // "run" mode: show a live dashboard instead of the children's output
+    pub model: String,                        // Model for "explore" mode, e.g. "p=2,t=2,drops=1,crashes=1"
+    pub sweep: String,                        // Sweep spec for "sweep" mode, e.g. "S=1.0,0.9;p=3,5"
+    pub repeat: u32,                          // Number of times each sweep configuration is run
 }
 
 impl TPCOptions {
@@ -33,6 +65,7 @@ impl TPCOptions {
     /// new()
     /// Returns a new options structure containing CLI options / defaults
     ///
+    #[allow(clippy::new_without_default)]
     pub fn new() -> TPCOptions {
 
         // Default values for CLI options
@@ -42,10 +75,28 @@ impl TPCOptions {
         let default_num_clients = "3";
         let default_num_requests = "15";
         let default_verbosity = "0";
+        let default_log_output = "text";
         let default_mode = "run";
         let default_log_path = "./logs/";
         let default_ipc_path = "none";
         let default_num = "0";
+        let default_arrival = "poisson";
+        let default_rate = "0";
+        let default_think_time = "100";
+        let default_workload = "none";
+        let default_accounts = "100";
+        let default_initial_balance = "1000";
+        let default_log_format = "json";
+        let default_durability = "flush";
+        let default_segment_size = "0";
+        let default_checkpoint_interval = "0";
+        let default_log_file = "";
+        let default_filter = "";
+        let default_on_violation = "log";
+        let default_metrics_addr = "";
+        let default_model = "";
+        let default_sweep = "";
+        let default_repeat = "1";
 
         // Set-Up clap
         let matches = App::new("concurrency-2pc")
@@ -82,6 +133,11 @@ impl TPCOptions {
                     .required(false)
                     .takes_value(true)
                     .help("Output verbosity: 0->No Output, 5->Output Everything"))
+            .arg(Arg::with_name("log_output")
+                    .long("log_output")
+                    .required(false)
+                    .takes_value(true)
+                    .help("stderr log output: \"text\" or \"json\" (one object per line, carrying the txid, senderid, role and phase of each protocol event)"))
             .arg(Arg::with_name("log_path")
                     .short("l")
                     .required(false)
@@ -91,7 +147,7 @@ impl TPCOptions {
                     .short("m")
                     .required(false)
                     .takes_value(true)
-                    .help("Mode: \"run\" starts 2PC, \"client\" starts a client process, \"participant\" starts a participant process, \"check\" checks logs produced by previous run"))
+                    .help("Mode: \"run\" starts 2PC, \"client\" starts a client process, \"participant\" starts a participant process, \"check\" checks logs produced by previous run, \"sweep\" runs and checks every configuration of --sweep, \"verify-log\" verifies the hash chains of logs, \"inspect\" prints log records, \"timeline\" merges all logs into per-transaction timelines, \"explore\" model checks the protocol state machines (see --model), \"graph\" prints the state machines in DOT with the transitions taken by the run in log_path"))
             .arg(Arg::with_name("ipc_path")
                     .long("ipc_path")
                     .required(false)
@@ -102,6 +158,112 @@ impl TPCOptions {
                     .required(false)
                     .takes_value(true)
                     .help("Participant / Client number for naming the log files. Ranges from 0 to num_clients - 1 or num_participants - 1"))
+            .arg(Arg::with_name("arrival")
+                    .long("arrival")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Open-loop arrival process: \"constant\" or \"poisson\". Only used together with --rate"))
+            .arg(Arg::with_name("rate")
+                    .long("rate")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Open-loop request rate per client, e.g. \"500/s\" or \"30/m\". 0 runs closed-loop clients"))
+            .arg(Arg::with_name("think_time")
+                    .long("think_time")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Closed-loop think time in ms between receiving a result and issuing the next request"))
+            .arg(Arg::with_name("workload")
+                    .long("workload")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Workload: \"none\" runs opaque operations, \"bank\" runs transfers between accounts sharded across participants"))
+            .arg(Arg::with_name("accounts")
+                    .long("accounts")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Number of bank accounts for the bank workload"))
+            .arg(Arg::with_name("initial_balance")
+                    .long("initial_balance")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Starting balance of every account for the bank workload"))
+            .arg(Arg::with_name("log_format")
+                    .long("log_format")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Format of the coordinator and participant logs: \"json\" lines or length-prefixed, CRC-checked \"binary\" records"))
+            .arg(Arg::with_name("durability")
+                    .long("durability")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Log durability: \"none\" buffers, \"flush\" flushes every record, \"fsync\" syncs every record, \"group:<records>:<ms>\" syncs every <records> records or <ms> ms. Decisions and votes are always synced unless \"none\""))
+            .arg(Arg::with_name("segment_size")
+                    .long("segment_size")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Start a new log segment once a segment reaches this many bytes (K/M/G suffixes allowed). 0 keeps a single file"))
+            .arg(Arg::with_name("checkpoint_interval")
+                    .long("checkpoint_interval")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Checkpoint logs every N records, dropping decided transactions and older segments. 0 never checkpoints; the checker needs full logs"))
+            .arg(Arg::with_name("log_file")
+                    .long("log_file")
+                    .required(false)
+                    .takes_value(true)
+                    .help("OpLog file to read in verify-log and inspect modes. Defaults to the coordinator and participant logs in log_path"))
+            .arg(Arg::with_name("txid")
+                    .long("txid")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Inspect and timeline modes: only show records of this transaction"))
+            .arg(Arg::with_name("mtype")
+                    .long("mtype")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Inspect mode: only show records of this message type, e.g. CoordinatorCommit"))
+            .arg(Arg::with_name("sender")
+                    .long("sender")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Inspect mode: only show records of this sender"))
+            .arg(Arg::with_name("lsn")
+                    .long("lsn")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Inspect mode: only show records in this LSN range: \"a..b\" (inclusive), \"a..\", \"..b\" or \"a\""))
+            .arg(Arg::with_name("on_violation")
+                    .long("on_violation")
+                    .required(false)
+                    .takes_value(true)
+                    .help("What the coordinator does when its online safety monitor catches a broken invariant: \"log\" logs it with the transaction's votes, \"halt\" also drops the offending decision and stops the run"))
+            .arg(Arg::with_name("metrics_addr")
+                    .long("metrics_addr")
+                    .alias("metrics-addr")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Serve Prometheus metrics on this loopback address, e.g. 127.0.0.1:9100. The coordinator binds it, participant_<n> binds the port + 1 + n"))
+            .arg(Arg::with_name("tui")
+                    .long("tui")
+                    .required(false)
+                    .takes_value(false)
+                    .help("Run mode: show a live dashboard of the run. The output of every process goes to <log_path>/<id>.out instead of the terminal"))
+            .arg(Arg::with_name("model")
+                    .long("model")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Explore mode: size of the model to check, e.g. \"p=2,t=2,drops=1,crashes=1,states=1000000\" (participants, transactions, message drops, crashes, state limit). Missing keys take these defaults"))
+            .arg(Arg::with_name("sweep")
+                    .long("sweep")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Sweep spec for sweep mode: ';'-separated axes of comma-separated values, e.g. \"S=1.0,0.95,0.9;s=1.0,0.9;p=3,5,9\""))
+            .arg(Arg::with_name("repeat")
+                    .long("repeat")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Number of times each sweep configuration is run"))
             .get_matches();
 
         // Parse CLI options and take default values if none given
@@ -112,12 +274,93 @@ impl TPCOptions {
         let num_participants = matches.value_of("num_participants").unwrap_or(default_num_participants).parse::<u32>().unwrap();
         let num_requests = matches.value_of("num_requests").unwrap_or(default_num_requests).parse::<u32>().unwrap();
         let verbosity = matches.value_of("verbosity").unwrap_or(default_verbosity).parse::<usize>().unwrap();
+        let log_output = matches.value_of("log_output").unwrap_or(default_log_output);
         let log_path = matches.value_of("log_path").unwrap_or(default_log_path);
         let ipc_path = matches.value_of("ipc_path").unwrap_or(default_ipc_path);
         let num = matches.value_of("num").unwrap_or(default_num).parse::<u32>().unwrap();
+        let arrival = matches.value_of("arrival").unwrap_or(default_arrival);
+        let rate = workload::parse_rate(matches.value_of("rate").unwrap_or(default_rate)).unwrap();
+        let think_time = matches.value_of("think_time").unwrap_or(default_think_time).parse::<u64>().unwrap();
+        let workload = matches.value_of("workload").unwrap_or(default_workload);
+        let accounts = matches.value_of("accounts").unwrap_or(default_accounts).parse::<u32>().unwrap();
+        let initial_balance = matches.value_of("initial_balance").unwrap_or(default_initial_balance).parse::<i64>().unwrap();
+        let log_format = matches.value_of("log_format").unwrap_or(default_log_format);
+        let durability = matches.value_of("durability").unwrap_or(default_durability);
+        let segment_size = matches.value_of("segment_size").unwrap_or(default_segment_size);
+        let checkpoint_interval = matches.value_of("checkpoint_interval").unwrap_or(default_checkpoint_interval).parse::<u32>().unwrap();
+        let log_file = matches.value_of("log_file").unwrap_or(default_log_file);
+        let txid = matches.value_of("txid").unwrap_or(default_filter);
+        let mtype = matches.value_of("mtype").unwrap_or(default_filter);
+        let sender = matches.value_of("sender").unwrap_or(default_filter);
+        let lsn = matches.value_of("lsn").unwrap_or(default_filter);
+        let on_violation = matches.value_of("on_violation").unwrap_or(default_on_violation);
+        let metrics_addr = matches.value_of("metrics_addr").unwrap_or(default_metrics_addr);
+        let tui = matches.is_present("tui");
+        let model = matches.value_of("model").unwrap_or(default_model);
+        let sweep = matches.value_of("sweep").unwrap_or(default_sweep);
+        let repeat = matches.value_of("repeat").unwrap_or(default_repeat).parse::<u32>().unwrap();
+
+        match arrival {
+            "constant" | "poisson" => {},
+            _ => panic!("unknown arrival process requested!"),
+        }
+
+        match log_output {
+            "text" | "json" => {},
+            _ => panic!("unknown log output requested!"),
+        }
+
+        match log_format {
+            "json" | "binary" => {},
+            _ => panic!("unknown log format requested!"),
+        }
+
+        if oplog::Durability::parse(durability).is_none() {
+            panic!("unknown durability requested!");
+        }
+
+        if oplog::parse_size(segment_size).is_none() {
+            panic!("invalid segment size requested!");
+        }
+
+        if mtype != default_filter && message::MessageType::parse(mtype).is_none() {
+            panic!("unknown message type requested!");
+        }
+
+        if lsn != default_filter && inspect::parse_lsn_range(lsn).is_none() {
+            panic!("invalid LSN range requested!");
+        }
+
+        if monitor::OnViolation::parse(on_violation).is_none() {
+            panic!("unknown violation handling requested!");
+        }
+
+        if metrics_addr != default_metrics_addr {
+            match metrics_addr.parse::<SocketAddr>() {
+                Ok(addr) if addr.ip().is_loopback() => {
+                    if addr.port() as u32 + num_participants > u16::MAX as u32 {
+                        panic!("No room for the participants' metrics ports after the metrics address!");
+                    }
+                },
+                _ => panic!("The metrics address must be a loopback address and port!"),
+            }
+        }
+
+        match workload {
+            "none" => {},
+            "bank" => {
+                if accounts < 2 || accounts < num_participants {
+                    panic!("The bank workload needs at least 2 accounts and one account per participant");
+                }
+                if initial_balance < 1 {
+                    panic!("The bank workload needs a positive initial balance");
+                }
+            },
+            _ => panic!("unknown workload requested!"),
+        }
 
         // IPC path is necessary for client / participant to communicate with the coordinator
-        match mode.as_ref() {
+        match mode {
             "run" => {},
             "client" => {
                 if ipc_path == default_ipc_path {
@@ -130,20 +373,56 @@ impl TPCOptions {
                 }
             },
             "check" => {},
+            "verify-log" => {},
+            "inspect" => {},
+            "timeline" => {},
+            "graph" => {},
+            "explore" => {
+                if let Err(e) = explore::ModelConfig::parse(model) {
+                    panic!("{}", e);
+                }
+            },
+            "sweep" => {
+                if sweep == default_sweep {
+                    panic!("No sweep spec specified for sweep mode");
+                }
+            },
             _ => panic!("unknown execution mode requested!"),
         }
 
         TPCOptions {
-            send_success_probability: send_success_probability,
-            operation_success_probability: operation_success_probability,
-            num_clients: num_clients,
-            num_participants: num_participants,
-            num_requests: num_requests,
-            verbosity: verbosity,
+            send_success_probability,
+            operation_success_probability,
+            num_clients,
+            num_participants,
+            num_requests,
+            verbosity,
+            log_output: log_output.to_string(),
             mode: mode.to_string(),
             log_path: log_path.to_string(),
             ipc_path: ipc_path.to_string(),
-            num: num,
+            num,
+            arrival: arrival.to_string(),
+            rate,
+            think_time,
+            workload: workload.to_string(),
+            accounts,
+            initial_balance,
+            log_format: log_format.to_string(),
+            durability: durability.to_string(),
+            segment_size: segment_size.to_string(),
+            checkpoint_interval,
+            log_file: log_file.to_string(),
+            txid: txid.to_string(),
+            mtype: mtype.to_string(),
+            sender: sender.to_string(),
+            lsn: lsn.to_string(),
+            on_violation: on_violation.to_string(),
+            metrics_addr: metrics_addr.to_string(),
+            tui,
+            model: model.to_string(),
+            sweep: sweep.to_string(),
+            repeat,
         }
     }
 
@@ -152,18 +431,38 @@ impl TPCOptions {
     ///
     /// HINT: This can be useful for passing arguments to children processes
     ///       using the std::process::Command's args(..) function
+    ///
+    /// The sweep options, log_file, the inspect filters and tui are left out:
+    /// they only matter to the process they are given to, never to the runs
+    /// it spawns.
     pub fn as_vec(&self) -> Vec<String> {
-        vec![
+        let mut args = vec![
             format!("-S{}", self.send_success_probability),
             format!("-s{}", self.operation_success_probability),
             format!("-c{}", self.num_clients),
             format!("-r{}", self.num_requests),
             format!("-p{}", self.num_participants),
             format!("-v{}", self.verbosity),
+            format!("--log_output={}", self.log_output),
             format!("-m{}", self.mode),
             format!("-l{}", self.log_path),
             format!("--ipc_path={}", self.ipc_path),
             format!("--num={}", self.num),
-        ]
+            format!("--arrival={}", self.arrival),
+            format!("--rate={}", self.rate),
+            format!("--think_time={}", self.think_time),
+            format!("--workload={}", self.workload),
+            format!("--accounts={}", self.accounts),
+            format!("--initial_balance={}", self.initial_balance),
+            format!("--log_format={}", self.log_format),
+            format!("--durability={}", self.durability),
+            format!("--segment_size={}", self.segment_size),
+            format!("--checkpoint_interval={}", self.checkpoint_interval),
+            format!("--on_violation={}", self.on_violation),
+        ];
+        if !self.metrics_addr.is_empty() {
+            args.push(format!("--metrics_addr={}", self.metrics_addr));
+        }
+        args
     }
 }
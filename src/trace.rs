@@ -0,0 +1,230 @@
+//!
+//! trace.rs
+//! Per-transaction spans in the Chrome trace-event format. Every role records
+//! where the time of each transaction goes (client request, proposal fan-out,
+//! vote collection and receipt, perform_operation, decision fan-out) and at
+//! exit writes them to log_path. The run mode merges all processes into
+//! trace.json, which chrome://tracing or ui.perfetto.dev can open.
+//!
+extern crate serde;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+///
+/// TraceEvent
+/// One entry of the traceEvents array; see the Trace Event Format spec
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct TraceEvent {
+    pub name: String,
+    pub cat: String,
+    pub ph: String,                 // "X" span, "b"/"e" async span, "i" instant, "M" metadata
+    pub ts: u64,                    // Microseconds since the Unix epoch, shared by all processes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dur: Option<u64>,
+    pub pid: u32,
+    pub tid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,         // Groups the begin and end of an async span
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s: Option<String>,          // Scope of an instant event
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub args: BTreeMap<String, String>,
+}
+
+///
+/// TraceFile
+/// Contents of a trace file in the JSON object format
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct TraceFile {
+    #[serde(rename = "traceEvents")]
+    pub trace_events: Vec<TraceEvent>,
+    #[serde(rename = "displayTimeUnit", default)]
+    pub display_time_unit: String,
+}
+
+impl TraceFile {
+
+    ///
+    /// write_json(path)
+    /// Writes the trace to the given file
+    ///
+    pub fn write_json(&self, path: &str) -> io::Result<()> {
+        let f = File::create(path)?;
+        serde_json::to_writer(f, self)?;
+        Ok(())
+    }
+
+    ///
+    /// from_file(path)
+    /// Reads a trace previously saved with write_json
+    ///
+    pub fn from_file(path: &str) -> io::Result<TraceFile> {
+        let f = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(f))?)
+    }
+}
+
+///
+/// Tracer
+/// Collects the trace events of one role. Times are taken as Instants and
+/// converted to wall clock time relative to when the tracer was created.
+///
+#[derive(Debug)]
+pub struct Tracer {
+    id: String,
+    pid: u32,
+    anchor: Instant,
+    anchor_us: u64,
+    events: Vec<TraceEvent>,
+}
+
+impl Tracer {
+
+    ///
+    /// new(id)
+    /// An empty trace for the role `id`, running in this process
+    ///
+    pub fn new(id: &str) -> Tracer {
+        let pid = process::id();
+        let mut args = BTreeMap::new();
+        args.insert("name".to_string(), id.to_string());
+        let process_name = TraceEvent {
+            name: "process_name".to_string(),
+            cat: String::new(),
+            ph: "M".to_string(),
+            ts: 0,
+            dur: None,
+            pid,
+            tid: 0,
+            id: None,
+            s: None,
+            args,
+        };
+        Tracer {
+            id: id.to_string(),
+            pid,
+            anchor: Instant::now(),
+            anchor_us: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0),
+            events: vec![process_name],
+        }
+    }
+
+    ///
+    /// ts(at)
+    /// Wall clock time of an Instant in microseconds since the Unix epoch
+    ///
+    fn ts(&self, at: Instant) -> u64 {
+        if at >= self.anchor {
+            self.anchor_us + (at - self.anchor).as_micros() as u64
+        } else {
+            self.anchor_us.saturating_sub((self.anchor - at).as_micros() as u64)
+        }
+    }
+
+    ///
+    /// event(name, ph, txid, at)
+    /// An event of this role about transaction txid
+    ///
+    fn event(&self, name: &str, ph: &str, txid: &str, at: Instant) -> TraceEvent {
+        let mut args = BTreeMap::new();
+        args.insert("txid".to_string(), txid.to_string());
+        TraceEvent {
+            name: name.to_string(),
+            cat: self.id.clone(),
+            ph: ph.to_string(),
+            ts: self.ts(at),
+            dur: None,
+            pid: self.pid,
+            tid: 0,
+            id: None,
+            s: None,
+            args,
+        }
+    }
+
+    ///
+    /// span(name, txid, start, end)
+    /// A span of this role's only thread; spans must nest or not overlap
+    ///
+    pub fn span(&mut self, name: &str, txid: &str, start: Instant, end: Instant) {
+        let mut e = self.event(name, "X", txid, start);
+        e.dur = Some(end.saturating_duration_since(start).as_micros() as u64);
+        self.events.push(e);
+    }
+
+    ///
+    /// async_span(name, txid, start, end)
+    /// A span that may overlap others, e.g. concurrent client requests;
+    /// viewers show each txid on its own track
+    ///
+    pub fn async_span(&mut self, name: &str, txid: &str, start: Instant, end: Instant) {
+        let mut begin = self.event(name, "b", txid, start);
+        begin.id = Some(txid.to_string());
+        let mut finish = self.event(name, "e", txid, end);
+        finish.id = Some(txid.to_string());
+        self.events.push(begin);
+        self.events.push(finish);
+    }
+
+    ///
+    /// instant(name, txid, at, detail)
+    /// A point event, e.g. the receipt of a vote
+    ///
+    pub fn instant(&mut self, name: &str, txid: &str, at: Instant, detail: &str) {
+        let mut e = self.event(name, "i", txid, at);
+        e.s = Some("t".to_string());
+        e.args.insert("detail".to_string(), detail.to_string());
+        self.events.push(e);
+    }
+
+    ///
+    /// to_file()
+    /// The recorded events as a trace file
+    ///
+    pub fn to_file(&self) -> TraceFile {
+        TraceFile {
+            trace_events: self.events.clone(),
+            display_time_unit: "ms".to_string(),
+        }
+    }
+}
+
+///
+/// merge(paths)
+/// The events of several trace files in one trace; files that cannot be read
+/// are skipped with a warning
+///
+pub fn merge(paths: &[String]) -> TraceFile {
+    let mut merged = TraceFile { trace_events: Vec::new(), display_time_unit: "ms".to_string() };
+    for path in paths {
+        match TraceFile::from_file(path) {
+            Ok(t) => merged.trace_events.extend(t.trace_events),
+            Err(e) => warn!("No trace at \"{}\": {:?}", path, e),
+        }
+    }
+    merged
+}
+
+///
+/// trace_path(log_path, id)
+/// Location of the trace of the role `id` in `log_path`
+///
+pub fn trace_path(log_path: &str, id: &str) -> String {
+    format!("{}//{}.trace.json", log_path, id)
+}
+
+///
+/// merged_trace_path(log_path)
+/// Location of the trace of the whole run in `log_path`
+///
+pub fn merged_trace_path(log_path: &str) -> String {
+    format!("{}//{}", log_path, "trace.json")
+}
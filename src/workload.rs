@@ -0,0 +1,179 @@
+//!
+//! workload.rs
+//! Request arrival processes used by 2PC clients. A client is either closed
+//! loop (wait for the result of a request, think, issue the next one) or open
+//! loop (issue requests at the times given by a constant-rate or Poisson
+//! process regardless of how many results are still outstanding).
+//!
+extern crate rand;
+
+use std::time::Duration;
+
+use workload::rand::Rng;
+
+/// Lower bound for how long the coordinator waits for a new request before
+/// deciding the clients are done
+const MIN_IDLE_TIMEOUT_MS: u64 = 200;
+
+/// Slowest open-loop rate accepted, in requests per second (one per hour), so
+/// that inter-arrival gaps and idle timeouts stay well within a Duration
+const MIN_RATE: f64 = 1.0 / 3600.0;
+
+/// Number of mean inter-arrival gaps without any request after which the
+/// workload is considered finished
+const IDLE_GAPS: u32 = 10;
+
+///
+/// Arrival
+/// Process that decides when a client issues its next request
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arrival {
+    ThinkTime(Duration),    // Closed loop: wait for the result, then sleep
+    Constant(f64),          // Open loop: one request every 1/rate seconds
+    Poisson(f64),           // Open loop: exponential gaps with mean 1/rate seconds
+}
+
+impl Arrival {
+
+    ///
+    /// new(arrival, rate, think_time_ms)
+    /// Builds the arrival process from the CLI options. A rate of zero selects
+    /// the closed-loop client with the given think time.
+    ///
+    pub fn new(arrival: &str, rate: f64, think_time_ms: u64) -> Arrival {
+        if rate <= 0.0 {
+            return Arrival::ThinkTime(Duration::from_millis(think_time_ms));
+        }
+        match arrival {
+            "constant" => Arrival::Constant(rate),
+            "poisson" => Arrival::Poisson(rate),
+            _ => panic!("Unknown arrival process \"{}\"", arrival),
+        }
+    }
+
+    ///
+    /// is_open_loop()
+    /// True if requests are issued without waiting for outstanding results
+    ///
+    pub fn is_open_loop(&self) -> bool {
+        !matches!(*self, Arrival::ThinkTime(_))
+    }
+
+    ///
+    /// next_delay(rng)
+    /// Samples the gap before the next request. For a closed-loop client this
+    /// is the think time after a result has been received.
+    ///
+    pub fn next_delay<R: Rng>(&self, rng: &mut R) -> Duration {
+        match *self {
+            Arrival::ThinkTime(d) => d,
+            Arrival::Constant(rate) => Duration::from_secs_f64(1.0 / rate),
+            Arrival::Poisson(rate) => {
+                // Inverse transform sampling; 1 - U lies in (0, 1] so ln() is finite
+                let u: f64 = 1.0 - rng.gen::<f64>();
+                Duration::from_secs_f64(-u.ln() / rate)
+            }
+        }
+    }
+
+    ///
+    /// mean_delay()
+    /// Expected gap between two consecutive requests of one client
+    ///
+    pub fn mean_delay(&self) -> Duration {
+        match *self {
+            Arrival::ThinkTime(d) => d,
+            Arrival::Constant(rate) | Arrival::Poisson(rate) => Duration::from_secs_f64(1.0 / rate),
+        }
+    }
+
+    ///
+    /// idle_timeout()
+    /// How long to wait without a request (or result) before assuming the
+    /// other side has finished. Scales with the arrival process so that slow
+    /// rates and long think times do not end a run early.
+    ///
+    pub fn idle_timeout(&self) -> Duration {
+        let min = Duration::from_millis(MIN_IDLE_TIMEOUT_MS);
+        let idle = self.mean_delay() * IDLE_GAPS;
+        if idle > min { idle } else { min }
+    }
+}
+
+///
+/// parse_rate(s)
+/// Parses a request rate such as "500", "500/s", "30/m" or "2/ms" and returns
+/// it in requests per second. Zero selects closed-loop clients; any other
+/// rate must be at least one request per hour.
+///
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    let (count, unit) = match s.find('/') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, "s"),
+    };
+    let count = count.trim().parse::<f64>().map_err(|e| format!("Invalid rate \"{}\": {}", s, e))?;
+    let per_sec = match unit.trim() {
+        "ms" => count * 1000.0,
+        "s" | "sec" => count,
+        "m" | "min" => count / 60.0,
+        "h" => count / 3600.0,
+        u => return Err(format!("Invalid rate unit \"{}\" in \"{}\"", u, s)),
+    };
+    if per_sec < 0.0 || !per_sec.is_finite() {
+        return Err(format!("Invalid rate \"{}\"", s));
+    }
+    if per_sec > 0.0 && per_sec < MIN_RATE {
+        return Err(format!("Rate \"{}\" is below the minimum of 1/h", s));
+    }
+    Ok(per_sec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_are_converted_to_requests_per_second() {
+        assert_eq!(parse_rate("500"), Ok(500.0));
+        assert_eq!(parse_rate("500/s"), Ok(500.0));
+        assert_eq!(parse_rate(" 30 / min "), Ok(0.5));
+        assert_eq!(parse_rate("2/ms"), Ok(2000.0));
+        assert_eq!(parse_rate("7200/h"), Ok(2.0));
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("5/d").is_err());
+        assert!(parse_rate("inf").is_err());
+    }
+
+    #[test]
+    fn arrivals_follow_their_rate() {
+        assert_eq!(Arrival::new("poisson", 0.0, 15), Arrival::ThinkTime(Duration::from_millis(15)));
+        let constant = Arrival::new("constant", 200.0, 0);
+        assert!(constant.is_open_loop());
+        assert_eq!(constant.next_delay(&mut rand::thread_rng()), Duration::from_millis(5));
+
+        let poisson = Arrival::new("poisson", 1000.0, 0);
+        let mut rng = rand::thread_rng();
+        let total: Duration = (0..10_000).map(|_| poisson.next_delay(&mut rng)).sum();
+        let mean_us = total.as_micros() / 10_000;
+        assert!((800..1200).contains(&mean_us), "mean gap {}us for 1000/s", mean_us);
+        assert_eq!(poisson.idle_timeout(), Duration::from_millis(MIN_IDLE_TIMEOUT_MS));
+    }
+
+    #[test]
+    fn rates_below_the_minimum_are_rejected() {
+        assert_eq!(parse_rate("0"), Ok(0.0));
+        assert_eq!(parse_rate("1/h"), Ok(MIN_RATE));
+        assert!(parse_rate("0.5/h").is_err());
+        assert!(parse_rate("1e-300").is_err());
+
+        let slowest = Arrival::new("poisson", parse_rate("1/h").unwrap(), 0);
+        assert_eq!(slowest.mean_delay(), Duration::from_secs(3600));
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            slowest.next_delay(&mut rng);
+        }
+        slowest.idle_timeout();
+    }
+}